@@ -8,13 +8,14 @@ fn main() {
     logger.filter(Some("archive"), log::LevelFilter::Debug);
     logger.init();
 
-    let mut reader = ArchiveReader::builder().path("archive.tar.gz").build();
+    let mut reader = ArchiveReader::builder()
+        .source("archive.tar.gz")
+        .open()
+        .unwrap();
     let base_dir = PathBuf::from("target/run");
 
-    reader.open().unwrap();
-
     info!("Hello!");
-    for file in reader.entries().unwrap() {
+    while let Some(mut file) = reader.next_entry().unwrap() {
         info!("Found: {:?}", file.archive_path().display(),);
 
         let extracted = file.extract(Some(&base_dir));
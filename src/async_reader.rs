@@ -0,0 +1,602 @@
+use std::future::Future;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+
+use bon::bon;
+use log::{debug, error};
+use tokio::io::{AsyncRead, AsyncReadExt, ReadBuf};
+use tokio::sync::mpsc::error::SendError;
+use tokio::sync::mpsc::OwnedPermit;
+use tokio::sync::{mpsc, oneshot};
+
+use crate::error::{Error, Result};
+use crate::extract::{ExtractOptions, ExtractReport};
+use crate::reader::{ArchiveEntry, EntryMetadata};
+use crate::{ArchiveOptions, ArchiveReader, ArchiveSource};
+
+/// How many chunks of data may be in flight between the
+/// blocking thread and the runtime, per channel
+const CHANNEL_CHUNKS: usize = 4;
+
+/// An [`ArchiveReader`] driven from async code.
+///
+/// `libarchive` only offers blocking calls, so the reader
+/// lives on a blocking thread (see
+/// [`tokio::task::spawn_blocking`]) and exchanges headers
+/// and data with the runtime through bounded channels.
+/// At most a few chunks of `chunk_size` bytes are held in
+/// memory at any time, however large the archive.
+///
+/// ```no_run
+/// use archive::async_reader::AsyncArchiveReader;
+/// use tokio::io::AsyncReadExt;
+///
+/// # async fn run() -> Result<(), archive::error::Error> {
+/// let mut reader = AsyncArchiveReader::builder()
+///     .source("archive.tar.gz")
+///     .open()
+///     .await?;
+///
+/// while let Some(mut entry) = reader.next_entry().await? {
+///     let mut data = Vec::new();
+///     entry.read_to_end(&mut data).await?;
+/// }
+/// # Ok(())
+/// # }
+/// ```
+///
+/// Dropping a future returned by the reader before it
+/// completes does not disturb later calls, but the
+/// request may still be carried out: e.g. the entry a
+/// dropped [`AsyncArchiveReader::next_entry`] would have
+/// returned is skipped.
+pub struct AsyncArchiveReader {
+    commands: mpsc::Sender<Command>,
+}
+
+/// A request to the blocking thread, which answers it
+/// through the channel it carries. Answers that nobody
+/// waits for anymore are dropped
+enum Command {
+    Next(oneshot::Sender<Result<Option<EntryHeader>>>),
+    /// Streams the data of the current entry
+    Data(mpsc::Sender<Result<Vec<u8>>>),
+    Extract(PathBuf, oneshot::Sender<Result<usize>>),
+    ExtractAll(
+        PathBuf,
+        ExtractOptions,
+        oneshot::Sender<Result<ExtractReport>>,
+    ),
+    Close(oneshot::Sender<Result<()>>),
+}
+
+/// What the runtime gets to know about an entry
+struct EntryHeader {
+    path: PathBuf,
+    metadata: EntryMetadata,
+    warning: Option<String>,
+}
+
+#[bon]
+impl AsyncArchiveReader {
+    /// Opens the archive read from `source` on a blocking
+    /// thread. Refer to [`ArchiveReader::builder`] for the
+    /// meaning of each option
+    #[builder(finish_fn = open)]
+    pub async fn new(
+        /// Where to read the archive from. Use
+        /// [`ArchiveSource::async_reader`] for
+        /// [`AsyncRead`] sources
+        #[builder(into)]
+        source: ArchiveSource,
+
+        /// The size of the chunks that data is read
+        /// and sent to the runtime in. Set to 1KiB by
+        /// default
+        #[builder(default = 1024)]
+        chunk_size: usize,
+
+        /// Set of options to be passed for the
+        /// handle. Refer to [`crate::core::ArchiveOptions`]
+        /// for more information
+        #[builder(default)]
+        handle_opts: ArchiveOptions,
+    ) -> Result<Self> {
+        let (commands, command_rx) = mpsc::channel(1);
+        let (opened_tx, opened) = oneshot::channel();
+
+        tokio::task::spawn_blocking(move || {
+            let reader = ArchiveReader::builder()
+                .source(source)
+                .chunk_size(chunk_size)
+                .handle_opts(handle_opts)
+                .open();
+
+            match reader {
+                Ok(reader) => {
+                    if opened_tx.send(Ok(())).is_ok() {
+                        serve(reader, command_rx, chunk_size);
+                    }
+                }
+                Err(e) => {
+                    let _ = opened_tx.send(Err(e));
+                }
+            }
+        });
+
+        opened.await.map_err(|_| worker_gone())??;
+
+        Ok(Self { commands })
+    }
+}
+
+impl AsyncArchiveReader {
+    /// Advances to the next entry of the archive. Refer to
+    /// [`ArchiveReader::next_entry`] for the details.
+    pub async fn next_entry(&mut self) -> Result<Option<AsyncArchiveEntry<'_>>> {
+        let header = self.request(Command::Next).await??;
+
+        Ok(header.map(|header| AsyncArchiveEntry {
+            reader: self,
+            header,
+            reserve: None,
+            data: None,
+            chunk: Vec::new(),
+            offset: 0,
+        }))
+    }
+
+    /// Extracts every entry of the archive below `dest`
+    /// without blocking the runtime. Refer to
+    /// [`ArchiveReader::extract_all`] for the details.
+    ///
+    /// The progress callback of `options` is called from
+    /// the blocking thread.
+    pub async fn extract_all<P: AsRef<Path>>(
+        &mut self,
+        dest: P,
+        options: &ExtractOptions,
+    ) -> Result<ExtractReport> {
+        let dest = dest.as_ref().to_path_buf();
+        let options = options.clone();
+
+        self.request(|reply| Command::ExtractAll(dest, options, reply))
+            .await?
+    }
+
+    /// Closes the archive. Refer to [`ArchiveReader::close`]
+    pub async fn close(self) -> Result<()> {
+        self.request(Command::Close).await?
+    }
+
+    /// Sends the command built by `command` around a fresh
+    /// reply channel, then waits for its answer
+    async fn request<T>(&self, command: impl FnOnce(oneshot::Sender<T>) -> Command) -> Result<T> {
+        let (reply, answer) = oneshot::channel();
+
+        if self.commands.send(command(reply)).await.is_err() {
+            return Err(worker_gone());
+        }
+
+        answer.await.map_err(|_| worker_gone())
+    }
+}
+
+/// An entry of an archive read by an
+/// [`AsyncArchiveReader`].
+///
+/// Its data is read through [`AsyncRead`]. Dropping the
+/// entry skips whatever data was not read.
+pub struct AsyncArchiveEntry<'a> {
+    reader: &'a mut AsyncArchiveReader,
+    header: EntryHeader,
+
+    /// Waits for room to ask for the entry's data
+    reserve: Option<Reserve>,
+    /// Receives the entry's data once it was first read
+    data: Option<mpsc::Receiver<Result<Vec<u8>>>>,
+    chunk: Vec<u8>,
+    offset: usize,
+}
+
+impl AsyncArchiveEntry<'_> {
+    /// The warning `libarchive` reported while reading
+    /// this entry's header, if any
+    pub fn warning(&self) -> Option<&str> {
+        self.header.warning.as_deref()
+    }
+
+    /// The path of this entry within the archive
+    pub fn archive_path(&self) -> &Path {
+        &self.header.path
+    }
+
+    /// Fetches the size of the entry
+    pub fn size(&self) -> i64 {
+        self.header.metadata.st_size
+    }
+
+    /// Fetches metadata about the entry
+    pub fn metadata(&self) -> &EntryMetadata {
+        &self.header.metadata
+    }
+
+    /// Extracts the entry onto an optional `base_dir`
+    /// without blocking the runtime. Refer to
+    /// [`ArchiveEntry::extract`] for the details.
+    ///
+    /// The data must not have been read yet, as only the
+    /// remainder would be written out, which fails with
+    /// [`std::io::ErrorKind::UnexpectedEof`].
+    pub async fn extract<P: AsRef<Path>>(&mut self, base_dir: Option<P>) -> Result<usize> {
+        let base_dir = match base_dir {
+            Some(base_dir) => base_dir.as_ref().to_path_buf(),
+            None => std::env::current_dir()?,
+        };
+
+        // The blocking thread may be waiting to send more
+        // data, which it stops doing once nobody listens
+        self.data = None;
+
+        self.reader
+            .request(|reply| Command::Extract(base_dir, reply))
+            .await?
+    }
+}
+
+impl AsyncRead for AsyncArchiveEntry<'_> {
+    /// Reads this entry's data, which the blocking thread
+    /// sends over in chunks
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+
+        if this.offset == this.chunk.len() {
+            let data = match &mut this.data {
+                Some(data) => data,
+                None => {
+                    // The slot may still be taken by a command the
+                    // blocking thread has not picked up yet, so wait
+                    // for it to free up rather than failing
+                    let commands = this.reader.commands.clone();
+                    let reserve = this
+                        .reserve
+                        .get_or_insert_with(|| Box::pin(commands.reserve_owned()));
+                    let permit = ready!(reserve.as_mut().poll(cx));
+                    this.reserve = None;
+
+                    // Only fails once the blocking thread stopped
+                    let Ok(permit) = permit else {
+                        return Poll::Ready(Err(worker_gone().into()));
+                    };
+
+                    let (data_tx, data) = mpsc::channel(CHANNEL_CHUNKS);
+                    permit.send(Command::Data(data_tx));
+
+                    this.data.insert(data)
+                }
+            };
+
+            match ready!(data.poll_recv(cx)) {
+                Some(Ok(chunk)) => {
+                    this.chunk = chunk;
+                    this.offset = 0;
+                }
+                Some(Err(e)) => return Poll::Ready(Err(e.into())),
+                // All of the data was read
+                None => return Poll::Ready(Ok(())),
+            }
+        }
+
+        let len = buf.remaining().min(this.chunk.len() - this.offset);
+        buf.put_slice(&this.chunk[this.offset..this.offset + len]);
+        this.offset += len;
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Waits for a free slot in the command channel
+type Reserve = Pin<
+    Box<
+        dyn Future<Output = std::result::Result<OwnedPermit<Command>, SendError<()>>> + Send + Sync,
+    >,
+>;
+
+/// The error returned once the blocking thread stopped,
+/// which only happens if it panicked
+fn worker_gone() -> Error {
+    std::io::Error::new(
+        std::io::ErrorKind::BrokenPipe,
+        "the archive reader thread stopped",
+    )
+    .into()
+}
+
+/// Answers commands from the runtime until the
+/// [`AsyncArchiveReader`] is dropped
+fn serve(mut reader: ArchiveReader, mut commands: mpsc::Receiver<Command>, chunk_size: usize) {
+    let mut pending = None;
+
+    loop {
+        let command = match pending.take().or_else(|| commands.blocking_recv()) {
+            Some(command) => command,
+            None => break,
+        };
+
+        match command {
+            Command::Next(reply) => match reader.next_entry() {
+                Ok(Some(mut entry)) => {
+                    let header = EntryHeader {
+                        path: entry.archive_path().to_path_buf(),
+                        metadata: entry.metadata().clone(),
+                        warning: entry.warning().map(str::to_string),
+                    };
+
+                    // The future asking for the entry was
+                    // dropped, so it is skipped
+                    if reply.send(Ok(Some(header))).is_err() {
+                        debug!("Skipping `{}`", entry.archive_path().display());
+                        continue;
+                    }
+
+                    // Serve the entry until the runtime moves on
+                    pending = serve_entry(&mut entry, &mut commands, chunk_size);
+
+                    if pending.is_none() {
+                        break;
+                    }
+                }
+                Ok(None) => {
+                    let _ = reply.send(Ok(None));
+                }
+                Err(e) => {
+                    let _ = reply.send(Err(e));
+                }
+            },
+            Command::ExtractAll(dest, options, reply) => {
+                let _ = reply.send(reader.extract_all(dest, &options));
+            }
+            Command::Close(reply) => {
+                let _ = reply.send(reader.close());
+                return;
+            }
+            // Only sent while an entry is alive. Dropping the
+            // reply channel reports the entry as gone
+            Command::Data(_) | Command::Extract(..) => continue,
+        }
+    }
+
+    debug!("Async archive reader dropped, closing the archive");
+}
+
+/// Answers the commands concerning `entry`, returning
+/// the first command that does not, or `None` once the
+/// [`AsyncArchiveReader`] was dropped
+fn serve_entry(
+    entry: &mut ArchiveEntry<'_>,
+    commands: &mut mpsc::Receiver<Command>,
+    chunk_size: usize,
+) -> Option<Command> {
+    loop {
+        match commands.blocking_recv()? {
+            Command::Data(data) => send_data(entry, data, chunk_size),
+            Command::Extract(base_dir, reply) => {
+                let _ = reply.send(entry.extract(Some(base_dir)));
+            }
+            command => return Some(command),
+        }
+    }
+}
+
+/// Sends the data of `entry` in chunks, until all of it
+/// was sent or the receiving end is dropped
+fn send_data(entry: &mut ArchiveEntry<'_>, data: mpsc::Sender<Result<Vec<u8>>>, chunk_size: usize) {
+    loop {
+        let mut chunk = vec![0; chunk_size];
+
+        match entry.read_data(&mut chunk) {
+            Ok(0) => return,
+            Ok(read) => {
+                chunk.truncate(read);
+
+                if data.blocking_send(Ok(chunk)).is_err() {
+                    return;
+                }
+            }
+            Err(e) => {
+                error!("Failed to read `{}`: {}", entry.archive_path().display(), e);
+                let _ = data.blocking_send(Err(e));
+                return;
+            }
+        }
+    }
+}
+
+impl ArchiveSource {
+    /// Reads the archive from an [`AsyncRead`] source.
+    ///
+    /// A task on the current runtime reads the source in
+    /// chunks, which the reader receives through a bounded
+    /// channel; the resulting [`ArchiveSource::Stream`]
+    /// must therefore only be read from a blocking thread,
+    /// e.g. by an [`AsyncArchiveReader`].
+    ///
+    /// # Panics
+    ///
+    /// This function panics when called outside of a
+    /// tokio runtime
+    pub fn async_reader(reader: impl AsyncRead + Unpin + Send + 'static) -> Self {
+        Self::stream(AsyncSource::spawn(reader))
+    }
+}
+
+/// The blocking end of an [`ArchiveSource::async_reader`]
+struct AsyncSource {
+    chunks: mpsc::Receiver<std::io::Result<Vec<u8>>>,
+    chunk: Vec<u8>,
+    offset: usize,
+}
+
+impl AsyncSource {
+    /// The size of the chunks read from the source
+    const CHUNK_SIZE: usize = 64 * 1024;
+
+    fn spawn(mut reader: impl AsyncRead + Unpin + Send + 'static) -> Self {
+        let (chunk_tx, chunks) = mpsc::channel(CHANNEL_CHUNKS);
+
+        tokio::spawn(async move {
+            loop {
+                let mut chunk = vec![0; Self::CHUNK_SIZE];
+
+                let result = reader.read(&mut chunk).await.map(|read| {
+                    chunk.truncate(read);
+                    chunk
+                });
+                let done = !matches!(&result, Ok(chunk) if !chunk.is_empty());
+
+                // The reader was dropped, or this was the end
+                if chunk_tx.send(result).await.is_err() || done {
+                    return;
+                }
+            }
+        });
+
+        Self {
+            chunks,
+            chunk: Vec::new(),
+            offset: 0,
+        }
+    }
+}
+
+impl Read for AsyncSource {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.offset == self.chunk.len() {
+            match self.chunks.blocking_recv() {
+                Some(chunk) => {
+                    self.chunk = chunk?;
+                    self.offset = 0;
+                }
+                None => return Ok(0),
+            }
+        }
+
+        let len = buf.len().min(self.chunk.len() - self.offset);
+        buf[..len].copy_from_slice(&self.chunk[self.offset..self.offset + len]);
+        self.offset += len;
+
+        Ok(len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn async_reader() {
+        let mut reader = AsyncArchiveReader::builder()
+            .source("archive.tar.gz")
+            .open()
+            .await
+            .unwrap();
+
+        let mut count = 0;
+        while let Some(mut entry) = reader.next_entry().await.unwrap() {
+            let mut data = Vec::new();
+            entry.read_to_end(&mut data).await.unwrap();
+
+            assert_eq!(data.len() as i64, entry.size());
+            count += 1;
+        }
+
+        assert!(count >= 1);
+        assert!(reader.close().await.is_ok());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn async_reader_extract() {
+        let dest = std::env::temp_dir().join("archive-async-extract");
+        let _ = std::fs::remove_dir_all(&dest);
+
+        let file = tokio::fs::File::open("archive.tar.gz").await.unwrap();
+        let mut reader = AsyncArchiveReader::builder()
+            .source(ArchiveSource::async_reader(file))
+            .open()
+            .await
+            .unwrap();
+
+        let report = reader
+            .extract_all(&dest, &ExtractOptions::default())
+            .await
+            .unwrap();
+        dbg!(&report);
+
+        assert!(report.is_ok());
+        assert!(report.entries >= 1);
+        assert_eq!(
+            std::fs::read(dest.join("LICENSE")).unwrap(),
+            std::fs::read("LICENSE").unwrap()
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn async_reader_cancelled() {
+        use std::future::Future;
+
+        let dest = std::env::temp_dir().join("archive-async-cancelled");
+        let _ = std::fs::remove_dir_all(&dest);
+
+        let mut reader = AsyncArchiveReader::builder()
+            .source("archive.tar.gz")
+            .open()
+            .await
+            .unwrap();
+
+        // Send the request for the next entry, then give up on it
+        {
+            let mut next = std::pin::pin!(reader.next_entry());
+            let mut cx = Context::from_waker(std::task::Waker::noop());
+            let _ = next.as_mut().poll(&mut cx);
+        }
+
+        let report = reader
+            .extract_all(&dest, &ExtractOptions::default())
+            .await
+            .unwrap();
+        assert!(report.is_ok());
+
+        assert!(reader.next_entry().await.unwrap().is_none());
+        assert!(reader.close().await.is_ok());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn async_reader_busy() {
+        use std::future::Future;
+
+        let dest = tempfile::tempdir().unwrap();
+        let mut reader = AsyncArchiveReader::builder()
+            .source("archive.tar.gz")
+            .open()
+            .await
+            .unwrap();
+        let mut entry = reader.next_entry().await.unwrap().unwrap();
+
+        // Queue a command, then read while it may
+        // still take up the slot in the channel
+        {
+            let mut extract = std::pin::pin!(entry.extract(Some(dest.path())));
+            let mut cx = Context::from_waker(std::task::Waker::noop());
+            let _ = extract.as_mut().poll(&mut cx);
+        }
+
+        let mut data = Vec::new();
+        entry.read_to_end(&mut data).await.unwrap();
+    }
+}
@@ -1,25 +1,58 @@
+use std::collections::HashSet;
+
 use bon::Builder;
 
-#[derive(Builder, Default)]
+use crate::error::{Error, Result};
+
+#[derive(Builder, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
 pub struct ArchiveOptions {
-    #[builder(default)]
-    /// The kind of filter to be used. The default
-    /// tells `libarchive` to analyze the file and
-    /// automatically determine the compression
-    /// filter.
+    #[builder(default, with = FromIterator::from_iter)]
+    /// The set of compression filters that `libarchive`
+    /// is allowed to use. The default (an empty set, or
+    /// any set containing [`ArchiveFilter::Auto`]) tells
+    /// `libarchive` to analyze the file and automatically
+    /// determine the compression filter.
+    ///
+    /// When reading, only the filters in this set are
+    /// registered with `libarchive`, and inputs that are
+    /// compressed with anything else are rejected with
+    /// [`crate::error::Error::FilterNotAllowed`]. Include
+    /// [`ArchiveFilter::None`] to accept uncompressed
+    /// input.
     ///
-    /// Note that the default value of this field
-    /// only works with [`crate::reader::ArchiveReader`]
-    pub(crate) filter: ArchiveFilter,
-
-    #[builder(default)]
-    /// The kind of format to be used. The default
-    /// tells `libarchive` to analyze the file and
-    /// automatically determine the archive format.
+    /// [`ArchiveFilter::Program`] cannot be used here, as
+    /// external programs need a command line; it fails
+    /// with [`crate::error::Error::UnsupportedFilter`].
     ///
-    /// Note that the default value of this field
-    /// only works with [`crate::reader::ArchiveReader`]
-    pub(crate) format: ArchiveFormat,
+    /// Writers accept at most one filter. By default, they
+    /// pick it from the extension of the archive's file
+    /// name (see [`crate::ArchiveWriter::new`]), and leave
+    /// archives not written to a file uncompressed
+    #[cfg_attr(feature = "serde", serde(serialize_with = "serialize_sorted"))]
+    pub(crate) filters: HashSet<ArchiveFilter>,
+
+    #[builder(default, with = FromIterator::from_iter)]
+    /// The set of archive formats that `libarchive` is
+    /// allowed to use. The default (an empty set, or
+    /// any set containing [`ArchiveFormat::Auto`]) tells
+    /// `libarchive` to analyze the file and automatically
+    /// determine the archive format.
+    ///
+    /// When reading, only the formats in this set are
+    /// registered with `libarchive`, and inputs in any
+    /// other format are rejected with
+    /// [`crate::error::Error::FormatNotAllowed`]. A format
+    /// family such as [`ArchiveFormat::Tar`] also allows
+    /// all of its variants.
+    ///
+    /// Writers accept at most one format. By default, they
+    /// pick it from the extension of the archive's file
+    /// name (see [`crate::ArchiveWriter::new`]); archives
+    /// not written to a file need one
+    #[cfg_attr(feature = "serde", serde(serialize_with = "serialize_sorted"))]
+    pub(crate) formats: HashSet<ArchiveFormat>,
 
     #[builder(default = 10240)]
     /// The buffer size of the handle. Similar
@@ -34,8 +67,83 @@ pub struct ArchiveOptions {
     pub(crate) handle_block_size: usize,
 }
 
+impl Default for ArchiveOptions {
+    fn default() -> Self {
+        Self::builder().build()
+    }
+}
+
+impl ArchiveOptions {
+    /// Whether every filter supported by `libarchive`
+    /// should be registered
+    pub(crate) fn any_filter(&self) -> bool {
+        self.filters.is_empty() || self.filters.contains(&ArchiveFilter::Auto)
+    }
+
+    /// Whether every format supported by `libarchive`
+    /// should be registered
+    pub(crate) fn any_format(&self) -> bool {
+        self.formats.is_empty() || self.formats.contains(&ArchiveFormat::Auto)
+    }
+
+    pub(crate) fn allows_filter(&self, filter: ArchiveFilter) -> bool {
+        self.any_filter() || self.filters.contains(&filter)
+    }
+
+    pub(crate) fn allows_format(&self, format: ArchiveFormat) -> bool {
+        self.any_format()
+            || self.formats.contains(&format)
+            || self.formats.contains(&format.family())
+    }
+
+    /// Returns the only filter in the set, for
+    /// handles that cannot use more than one
+    pub(crate) fn single_filter(&self) -> Result<ArchiveFilter> {
+        single(&self.filters)
+    }
+
+    /// Returns the only format in the set, for
+    /// handles that cannot use more than one
+    pub(crate) fn single_format(&self) -> Result<ArchiveFormat> {
+        single(&self.formats)
+    }
+}
+
+/// Serializes a set in a stable order, as the iteration
+/// order of a [`HashSet`] changes from run to run
+#[cfg(feature = "serde")]
+fn serialize_sorted<T, S>(set: &HashSet<T>, serializer: S) -> std::result::Result<S::Ok, S::Error>
+where
+    T: Ord + serde::Serialize,
+    S: serde::Serializer,
+{
+    let mut values: Vec<_> = set.iter().collect();
+    values.sort();
+
+    serializer.collect_seq(values)
+}
+
+fn single<T: Copy + Default>(set: &HashSet<T>) -> Result<T> {
+    let mut values = set.iter();
+
+    match (values.next(), values.next()) {
+        (None, _) => Ok(T::default()),
+        (Some(value), None) => Ok(*value),
+        (Some(_), Some(_)) => Err(Error::AmbiguousOptions),
+    }
+}
+
+/// A compression filter.
+///
+/// With the `serde` feature, filters are (de)serialized by
+/// their kebab-case name, e.g. `"gzip"` or `"zstd"`
 #[repr(u32)]
-#[derive(Debug, Default, PartialEq, Eq, Clone, Copy)]
+#[derive(Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Clone, Copy)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(rename_all = "kebab-case")
+)]
 pub enum ArchiveFilter {
     #[default]
     Auto = u32::MAX,
@@ -56,8 +164,18 @@ pub enum ArchiveFilter {
     Zstd = archive_sys::ARCHIVE_FILTER_ZSTD,
 }
 
+/// An archive format.
+///
+/// With the `serde` feature, formats are (de)serialized by
+/// their kebab-case name, e.g. `"tar-ustar"` or `"zip"`
+/// (7z is named `"7zip"`)
 #[repr(u32)]
-#[derive(Debug, Default, PartialEq, Eq, Clone, Copy)]
+#[derive(Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Clone, Copy)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(rename_all = "kebab-case")
+)]
 pub enum ArchiveFormat {
     #[default]
     Auto = u32::MAX,
@@ -90,7 +208,124 @@ pub enum ArchiveFormat {
     Lha = archive_sys::ARCHIVE_FORMAT_LHA,
     Cab = archive_sys::ARCHIVE_FORMAT_CAB,
     Rar = archive_sys::ARCHIVE_FORMAT_RAR,
+    #[cfg_attr(feature = "serde", serde(rename = "7zip"))]
     P7zip = archive_sys::ARCHIVE_FORMAT_7ZIP,
     Warc = archive_sys::ARCHIVE_FORMAT_WARC,
     RarV5 = archive_sys::ARCHIVE_FORMAT_RAR_V5,
 }
+
+impl ArchiveFilter {
+    /// Maps a filter code returned by `archive_filter_code(3)`
+    /// back onto an [`ArchiveFilter`]
+    pub(crate) fn from_code(code: i32) -> Option<Self> {
+        let filter = match code as u32 {
+            archive_sys::ARCHIVE_FILTER_NONE => Self::None,
+            archive_sys::ARCHIVE_FILTER_GZIP => Self::Gzip,
+            archive_sys::ARCHIVE_FILTER_BZIP2 => Self::Bzip2,
+            archive_sys::ARCHIVE_FILTER_COMPRESS => Self::Compress,
+            archive_sys::ARCHIVE_FILTER_PROGRAM => Self::Program,
+            archive_sys::ARCHIVE_FILTER_LZMA => Self::Lzma,
+            archive_sys::ARCHIVE_FILTER_XZ => Self::Xz,
+            archive_sys::ARCHIVE_FILTER_UU => Self::Uu,
+            archive_sys::ARCHIVE_FILTER_RPM => Self::Rpm,
+            archive_sys::ARCHIVE_FILTER_LZIP => Self::Lzip,
+            archive_sys::ARCHIVE_FILTER_LRZIP => Self::Lrzip,
+            archive_sys::ARCHIVE_FILTER_LZOP => Self::Lzop,
+            archive_sys::ARCHIVE_FILTER_GRZIP => Self::Grzip,
+            archive_sys::ARCHIVE_FILTER_LZ4 => Self::Lz4,
+            archive_sys::ARCHIVE_FILTER_ZSTD => Self::Zstd,
+            _ => return None,
+        };
+
+        Some(filter)
+    }
+}
+
+impl ArchiveFormat {
+    /// Maps a format code returned by `archive_format(3)`
+    /// back onto an [`ArchiveFormat`]
+    pub(crate) fn from_code(code: i32) -> Option<Self> {
+        let format = match code as u32 {
+            archive_sys::ARCHIVE_FORMAT_CPIO => Self::Cpio,
+            archive_sys::ARCHIVE_FORMAT_CPIO_POSIX => Self::CpioPosix,
+            archive_sys::ARCHIVE_FORMAT_CPIO_BIN_LE => Self::CpioBinLe,
+            archive_sys::ARCHIVE_FORMAT_CPIO_BIN_BE => Self::CpioBinBe,
+            archive_sys::ARCHIVE_FORMAT_CPIO_SVR4_NOCRC => Self::CpioSvr4Nocrc,
+            archive_sys::ARCHIVE_FORMAT_CPIO_SVR4_CRC => Self::CpioSvr4Crc,
+            archive_sys::ARCHIVE_FORMAT_CPIO_AFIO_LARGE => Self::CpioAfioLarge,
+            archive_sys::ARCHIVE_FORMAT_CPIO_PWB => Self::CpioPwb,
+            archive_sys::ARCHIVE_FORMAT_SHAR => Self::Shar,
+            archive_sys::ARCHIVE_FORMAT_SHAR_BASE => Self::SharBase,
+            archive_sys::ARCHIVE_FORMAT_SHAR_DUMP => Self::SharDump,
+            archive_sys::ARCHIVE_FORMAT_TAR => Self::Tar,
+            archive_sys::ARCHIVE_FORMAT_TAR_USTAR => Self::TarUstar,
+            archive_sys::ARCHIVE_FORMAT_TAR_PAX_INTERCHANGE => Self::TarPaxInterchange,
+            archive_sys::ARCHIVE_FORMAT_TAR_PAX_RESTRICTED => Self::TarPaxRestricted,
+            archive_sys::ARCHIVE_FORMAT_TAR_GNUTAR => Self::TarGnutar,
+            archive_sys::ARCHIVE_FORMAT_ISO9660 => Self::Iso9660,
+            archive_sys::ARCHIVE_FORMAT_ISO9660_ROCKRIDGE => Self::Iso9660Rockridge,
+            archive_sys::ARCHIVE_FORMAT_ZIP => Self::Zip,
+            archive_sys::ARCHIVE_FORMAT_EMPTY => Self::Empty,
+            archive_sys::ARCHIVE_FORMAT_AR => Self::Ar,
+            archive_sys::ARCHIVE_FORMAT_AR_GNU => Self::ArGnu,
+            archive_sys::ARCHIVE_FORMAT_AR_BSD => Self::ArBsd,
+            archive_sys::ARCHIVE_FORMAT_MTREE => Self::Mtree,
+            archive_sys::ARCHIVE_FORMAT_RAW => Self::Raw,
+            archive_sys::ARCHIVE_FORMAT_XAR => Self::Xar,
+            archive_sys::ARCHIVE_FORMAT_LHA => Self::Lha,
+            archive_sys::ARCHIVE_FORMAT_CAB => Self::Cab,
+            archive_sys::ARCHIVE_FORMAT_RAR => Self::Rar,
+            archive_sys::ARCHIVE_FORMAT_7ZIP => Self::P7zip,
+            archive_sys::ARCHIVE_FORMAT_WARC => Self::Warc,
+            archive_sys::ARCHIVE_FORMAT_RAR_V5 => Self::RarV5,
+            _ => return None,
+        };
+
+        Some(format)
+    }
+
+    /// Returns the format family this format belongs
+    /// to (e.g. [`ArchiveFormat::Tar`] for
+    /// [`ArchiveFormat::TarGnutar`])
+    pub fn family(self) -> Self {
+        if self == Self::Auto {
+            return self;
+        }
+
+        Self::from_code((self as u32 & archive_sys::ARCHIVE_FORMAT_BASE_MASK) as i32)
+            .unwrap_or(self)
+    }
+}
+
+#[cfg(all(test, feature = "serde"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names() {
+        let names =
+            serde_json::to_string(&[ArchiveFormat::TarUstar, ArchiveFormat::P7zip]).unwrap();
+        assert_eq!(names, r#"["tar-ustar","7zip"]"#);
+
+        let filter: ArchiveFilter = serde_json::from_str(r#""zstd""#).unwrap();
+        assert_eq!(filter, ArchiveFilter::Zstd);
+
+        let options: ArchiveOptions = serde_json::from_str(r#"{"formats":["zip"]}"#).unwrap();
+        assert!(options.allows_format(ArchiveFormat::Zip));
+        assert!(!options.allows_format(ArchiveFormat::Tar));
+        assert_eq!(options.handle_block_size, 10240);
+
+        // Sets come out in the same order every time
+        let options = ArchiveOptions::builder()
+            .formats([ArchiveFormat::Zip, ArchiveFormat::P7zip, ArchiveFormat::Tar])
+            .filters([
+                ArchiveFilter::Zstd,
+                ArchiveFilter::Gzip,
+                ArchiveFilter::None,
+            ])
+            .build();
+        let json = serde_json::to_value(&options).unwrap();
+        assert_eq!(json["formats"], serde_json::json!(["tar", "zip", "7zip"]));
+        assert_eq!(json["filters"], serde_json::json!(["none", "gzip", "zstd"]));
+    }
+}
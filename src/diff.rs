@@ -0,0 +1,787 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::File;
+use std::io::Read;
+use std::path::{Component, Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use bon::Builder;
+use log::{debug, warn};
+use sha2::{Digest, Sha256};
+
+use crate::entry::{EntryInfo, EntryKind};
+use crate::error::{Error, Result};
+use crate::extract::{is_fatal, EntryError};
+use crate::index::normalize;
+use crate::reader::{ArchiveEntry, ArchiveReader};
+use crate::writer::DiskReader;
+
+/// Options for [`ArchiveReader::compare_dir`] and
+/// [`ArchiveReader::diff`]
+#[derive(Builder, Clone)]
+pub struct CompareOptions {
+    #[builder(default)]
+    /// Whether to compare the contents of regular files
+    /// whose sizes match, which reads all of their data.
+    /// Between two archives, contents are compared by
+    /// their SHA-256 digest. Defaults to `false`,
+    /// comparing metadata only
+    pub(crate) contents: bool,
+
+    #[builder(default = true)]
+    /// Whether to compare permission bits. Defaults
+    /// to `true`
+    pub(crate) mode: bool,
+
+    #[builder(default = true)]
+    /// Whether to compare modification times, to the
+    /// second. Defaults to `true`
+    pub(crate) mtime: bool,
+
+    #[builder(default = true)]
+    /// Whether to compare owner and group ids. Defaults
+    /// to `true`
+    pub(crate) owner: bool,
+}
+
+impl Default for CompareOptions {
+    fn default() -> Self {
+        Self::builder().build()
+    }
+}
+
+/// The differences between an archive and what it is
+/// compared to, sorted by path.
+///
+/// The archive a comparison is made from is the old side:
+/// when comparing it against a directory, `removed` lists
+/// the entries missing on disk, and `added` the files on
+/// disk that are not in the archive.
+#[derive(Debug, Default)]
+pub struct Diff {
+    /// Paths that are only in the archive
+    pub removed: Vec<PathBuf>,
+    /// Paths that are only in what the archive is
+    /// compared to
+    pub added: Vec<PathBuf>,
+    /// Paths that are on both sides, but differ
+    pub modified: Vec<Modified>,
+    /// The entries that could not be compared
+    pub errors: Vec<EntryError>,
+}
+
+impl Diff {
+    /// Whether both sides are the same, and everything
+    /// could be compared
+    pub fn is_empty(&self) -> bool {
+        self.removed.is_empty()
+            && self.added.is_empty()
+            && self.modified.is_empty()
+            && self.errors.is_empty()
+    }
+}
+
+/// A path whose entry differs between both sides
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Modified {
+    pub path: PathBuf,
+    /// Everything that differs. When the kinds differ,
+    /// nothing else is compared
+    pub changes: Vec<Change>,
+}
+
+/// A difference between two entries at the same path,
+/// from the archive's (old) value to the new one
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Change {
+    Kind {
+        old: EntryKind,
+        new: EntryKind,
+    },
+    Size {
+        old: Option<u64>,
+        new: Option<u64>,
+    },
+    /// Permission bits, without the file type
+    Mode {
+        old: u32,
+        new: u32,
+    },
+    Mtime {
+        old: Option<SystemTime>,
+        new: Option<SystemTime>,
+    },
+    /// Owner and group ids
+    Owner {
+        old: (u64, u64),
+        new: (u64, u64),
+    },
+    /// The target of a symlink or hardlink
+    Link {
+        old: Option<PathBuf>,
+        new: Option<PathBuf>,
+    },
+    /// The contents of regular files of the same size
+    Contents,
+}
+
+/// Compares the entries of `reader`, from the start of the
+/// archive, with the tree below `dir`
+pub(crate) fn compare_dir(
+    reader: &mut ArchiveReader,
+    dir: &Path,
+    options: &CompareOptions,
+) -> Result<Diff> {
+    reader.rewind()?;
+
+    // Fails early if the directory is missing altogether
+    std::fs::read_dir(dir)?;
+
+    let disk = DiskReader::new()?;
+    let mut diff = Diff::default();
+    let mut seen = BTreeSet::new();
+    let mut buffer = vec![0; reader.chunk_size.max(1)];
+
+    // Whether every entry was read, without which there is
+    // no telling which files on disk were added
+    let mut complete = true;
+
+    loop {
+        let mut entry = match reader.next_entry() {
+            Ok(Some(entry)) => entry,
+            Ok(None) => break,
+            Err(error) => {
+                let fatal = is_fatal(&error);
+                diff.errors.push(EntryError { path: None, error });
+
+                if fatal {
+                    complete = false;
+                    break;
+                }
+
+                continue;
+            }
+        };
+
+        let path = entry.archive_path().to_path_buf();
+        let Some(relative) = relative(&path) else {
+            diff.errors.push(EntryError {
+                error: Error::UnsafePath(path.clone()),
+                path: Some(path),
+            });
+            continue;
+        };
+
+        // Parents are implied by their children, even when the
+        // archive has no entry for them
+        seen.extend(relative.ancestors().map(Path::to_path_buf));
+
+        match compare_entry(
+            &mut entry,
+            &disk,
+            &dir.join(&relative),
+            options,
+            &mut buffer,
+        ) {
+            Ok(Some(changes)) if changes.is_empty() => {}
+            Ok(Some(changes)) => diff.modified.push(Modified {
+                path: relative,
+                changes,
+            }),
+            Ok(None) => diff.removed.push(relative),
+            Err(error) => {
+                warn!("Failed to compare `{}`: {}", path.display(), error);
+
+                // Errors on disk (e.g. unreadable files) only
+                // concern this entry
+                let fatal = is_fatal(&error);
+
+                diff.errors.push(EntryError {
+                    path: Some(path),
+                    error,
+                });
+
+                if fatal {
+                    complete = false;
+                    break;
+                }
+            }
+        }
+    }
+
+    if complete {
+        walk(dir, Path::new(""), &seen, &mut diff.added)?;
+    }
+
+    diff.removed.sort();
+    diff.added.sort();
+    diff.modified.sort_by(|a, b| a.path.cmp(&b.path));
+
+    debug!(
+        "Compared archive with `{}`: {} removed, {} added, {} modified",
+        dir.display(),
+        diff.removed.len(),
+        diff.added.len(),
+        diff.modified.len()
+    );
+
+    Ok(diff)
+}
+
+/// Compares the entries of `old` with those of `new`, both
+/// from the start of their archive, matching them by path
+pub(crate) fn diff(
+    old: &mut ArchiveReader,
+    new: &mut ArchiveReader,
+    options: &CompareOptions,
+) -> Result<Diff> {
+    let mut diff = Diff::default();
+    let old = snapshot(old, options, &mut diff.errors)?;
+    let new = snapshot(new, options, &mut diff.errors)?;
+
+    for (path, (old, old_digest)) in &old {
+        let Some((new, new_digest)) = new.get(path) else {
+            diff.removed.push(path.clone());
+            continue;
+        };
+
+        let mut changes = changes(old, new, options);
+
+        // A digest is missing when the data could not be read,
+        // which was reported already
+        let differs = matches!((old_digest, new_digest), (Some(old), Some(new)) if old != new);
+
+        if options.contents && comparable(old, &changes) && differs {
+            changes.push(Change::Contents);
+        }
+
+        if !changes.is_empty() {
+            diff.modified.push(Modified {
+                path: path.clone(),
+                changes,
+            });
+        }
+    }
+
+    diff.added = new
+        .keys()
+        .filter(|path| !old.contains_key(*path))
+        .cloned()
+        .collect();
+
+    debug!(
+        "Compared archives: {} removed, {} added, {} modified",
+        diff.removed.len(),
+        diff.added.len(),
+        diff.modified.len()
+    );
+
+    Ok(diff)
+}
+
+/// The header of an entry, and the digest of its data
+/// when contents are compared and it could be read
+type Snapshot = (EntryInfo, Option<[u8; 32]>);
+
+/// Reads every entry of `reader`, keyed by path. When an
+/// archive holds the same path more than once, the last
+/// entry wins, as it would when extracting.
+///
+/// Entries whose data cannot be read are kept without a
+/// digest, so that they are still matched by path
+fn snapshot(
+    reader: &mut ArchiveReader,
+    options: &CompareOptions,
+    errors: &mut Vec<EntryError>,
+) -> Result<BTreeMap<PathBuf, Snapshot>> {
+    reader.rewind()?;
+
+    let mut entries = BTreeMap::new();
+    let mut buffer = vec![0; reader.chunk_size.max(1)];
+
+    loop {
+        let mut entry = match reader.next_entry() {
+            Ok(Some(entry)) => entry,
+            Ok(None) => break,
+            Err(error) => {
+                let fatal = is_fatal(&error);
+                errors.push(EntryError { path: None, error });
+
+                if fatal {
+                    break;
+                }
+
+                continue;
+            }
+        };
+
+        let mut info = entry.to_info();
+        info.path = normalize(&info.path);
+
+        let mut fatal = false;
+        let digest = if options.contents && info.kind == EntryKind::File {
+            match digest(&mut entry, &mut buffer) {
+                Ok(digest) => Some(digest),
+                Err(error) => {
+                    warn!("Failed to read `{}`: {}", info.path.display(), error);
+                    fatal = is_fatal(&error);
+
+                    errors.push(EntryError {
+                        path: Some(info.path.clone()),
+                        error,
+                    });
+
+                    None
+                }
+            }
+        } else {
+            None
+        };
+
+        entries.insert(info.path.clone(), (info, digest));
+
+        if fatal {
+            break;
+        }
+    }
+
+    Ok(entries)
+}
+
+fn digest(entry: &mut ArchiveEntry<'_>, buffer: &mut [u8]) -> Result<[u8; 32]> {
+    let mut hasher = Sha256::new();
+
+    loop {
+        let read = entry.read_data(buffer)?;
+
+        if read == 0 {
+            return Ok(hasher.finalize().into());
+        }
+
+        hasher.update(&buffer[..read]);
+    }
+}
+
+/// Compares `entry` with the file at `target`, returning
+/// `Ok(None)` if there is none
+fn compare_entry(
+    entry: &mut ArchiveEntry<'_>,
+    disk: &DiskReader,
+    target: &Path,
+    options: &CompareOptions,
+    buffer: &mut [u8],
+) -> Result<Option<Vec<Change>>> {
+    match std::fs::symlink_metadata(target) {
+        Ok(_) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+
+    let old = entry.to_info();
+    let raw = disk.entry(target, &old.path)?;
+    let new = unsafe { EntryInfo::from_raw(raw.0, old.path.clone()) };
+
+    // The data of a hardlink is stored with the entry it
+    // refers to, which is compared on its own
+    if old.kind == EntryKind::Hardlink {
+        return Ok(Some(if new.kind == EntryKind::File {
+            Vec::new()
+        } else {
+            vec![Change::Kind {
+                old: old.kind,
+                new: new.kind,
+            }]
+        }));
+    }
+
+    let mut changes = changes(&old, &new, options);
+
+    if options.contents
+        && comparable(&old, &changes)
+        && !same_contents(entry, &mut File::open(target)?, buffer)?
+    {
+        changes.push(Change::Contents);
+    }
+
+    Ok(Some(changes))
+}
+
+/// Lists the metadata that differs between `old` and `new`
+fn changes(old: &EntryInfo, new: &EntryInfo, options: &CompareOptions) -> Vec<Change> {
+    if old.kind != new.kind {
+        return vec![Change::Kind {
+            old: old.kind,
+            new: new.kind,
+        }];
+    }
+
+    let mut changes = Vec::new();
+
+    if old.kind == EntryKind::File && old.size != new.size {
+        changes.push(Change::Size {
+            old: old.size,
+            new: new.size,
+        });
+    }
+
+    if options.mode && old.mode != new.mode {
+        changes.push(Change::Mode {
+            old: old.mode,
+            new: new.mode,
+        });
+    }
+
+    // Most formats store whole seconds, so finer
+    // differences are not meaningful
+    if options.mtime && seconds(old.mtime) != seconds(new.mtime) {
+        changes.push(Change::Mtime {
+            old: old.mtime,
+            new: new.mtime,
+        });
+    }
+
+    if options.owner && (old.uid, old.gid) != (new.uid, new.gid) {
+        changes.push(Change::Owner {
+            old: (old.uid, old.gid),
+            new: (new.uid, new.gid),
+        });
+    }
+
+    let (old_link, new_link) = match old.kind {
+        EntryKind::Symlink => (&old.symlink, &new.symlink),
+        _ => (&old.hardlink, &new.hardlink),
+    };
+
+    if old_link != new_link {
+        changes.push(Change::Link {
+            old: old_link.clone(),
+            new: new_link.clone(),
+        });
+    }
+
+    changes
+}
+
+/// Whether the contents of two entries can differ even
+/// though `changes` are all their headers differ by
+fn comparable(old: &EntryInfo, changes: &[Change]) -> bool {
+    old.kind == EntryKind::File
+        && !changes
+            .iter()
+            .any(|change| matches!(change, Change::Kind { .. } | Change::Size { .. }))
+}
+
+/// Reads `entry`'s data alongside `file`, stopping at
+/// the first difference
+fn same_contents(entry: &mut ArchiveEntry<'_>, file: &mut File, buffer: &mut [u8]) -> Result<bool> {
+    let mut other = vec![0; buffer.len()];
+
+    loop {
+        let read = entry.read_data(buffer)?;
+
+        if read == 0 {
+            // Both have the same size, as recorded in the header
+            return Ok(file.read(&mut other[..1])? == 0);
+        }
+
+        if let Err(e) = file.read_exact(&mut other[..read]) {
+            return match e.kind() {
+                std::io::ErrorKind::UnexpectedEof => Ok(false),
+                _ => Err(e.into()),
+            };
+        }
+
+        if buffer[..read] != other[..read] {
+            return Ok(false);
+        }
+    }
+}
+
+/// Lists the paths below `dir` that are not in `seen`,
+/// without following symlinks
+fn walk(
+    dir: &Path,
+    relative: &Path,
+    seen: &BTreeSet<PathBuf>,
+    added: &mut Vec<PathBuf>,
+) -> Result<()> {
+    for child in std::fs::read_dir(dir.join(relative))? {
+        let child = child?;
+        let path = relative.join(child.file_name());
+
+        if !seen.contains(&path) {
+            added.push(path.clone());
+        }
+
+        if child.file_type()?.is_dir() {
+            walk(dir, &path, seen, added)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// The path of an entry relative to the root of the
+/// archive, or `None` if it would escape it
+fn relative(path: &Path) -> Option<PathBuf> {
+    let mut relative = PathBuf::new();
+
+    for component in path.components() {
+        match component {
+            Component::Normal(part) => relative.push(part),
+            Component::CurDir => {}
+            _ => return None,
+        }
+    }
+
+    Some(relative)
+}
+
+fn seconds(time: Option<SystemTime>) -> Option<i64> {
+    time.map(|time| match time.duration_since(UNIX_EPOCH) {
+        Ok(after) => after.as_secs() as i64,
+        Err(before) => -(before.duration().as_secs_f64().ceil() as i64),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::extract::ExtractOptions;
+    use crate::writer::{AddPathOptions, ArchiveWriter};
+
+    #[test]
+    fn compare_entry_errors() {
+        let base = std::env::temp_dir().join("archive-compare-errors");
+        let _ = std::fs::remove_dir_all(&base);
+        std::fs::create_dir_all(&base).unwrap();
+
+        let path = base.join("tree.tar");
+        let mut writer = ArchiveWriter::builder().path(&path).open().unwrap();
+        writer.add_dir("a").unwrap();
+        writer.add_bytes("a/b", b"b").unwrap();
+        writer.add_bytes("z1", b"z1").unwrap();
+        writer.add_bytes("z2", b"z2").unwrap();
+        writer.finish().unwrap();
+
+        let dir = base.join("tree");
+        let mut reader = ArchiveReader::builder().source(&path).open().unwrap();
+        assert!(reader
+            .extract_all(&dir, &ExtractOptions::default())
+            .unwrap()
+            .is_ok());
+
+        // `a/b` cannot be looked up once `a` is a file
+        std::fs::remove_dir_all(dir.join("a")).unwrap();
+        std::fs::write(dir.join("a"), "a").unwrap();
+
+        let options = CompareOptions::builder()
+            .mode(false)
+            .mtime(false)
+            .owner(false)
+            .build();
+        let diff = reader.compare_dir(&dir, &options).unwrap();
+        dbg!(&diff);
+
+        assert!(diff.added.is_empty());
+        assert!(diff.removed.is_empty());
+        assert_eq!(diff.errors.len(), 1);
+        assert_eq!(diff.errors[0].path.as_deref(), Some(Path::new("a/b")));
+
+        assert_eq!(diff.modified.len(), 1);
+        assert_eq!(diff.modified[0].path.as_os_str(), "a");
+        assert!(matches!(diff.modified[0].changes[0], Change::Kind { .. }));
+    }
+
+    #[test]
+    fn compare_extracted() {
+        let base = std::env::temp_dir().join("archive-compare");
+        let _ = std::fs::remove_dir_all(&base);
+
+        let mut reader = ArchiveReader::builder()
+            .source("archive.tar.gz")
+            .open()
+            .unwrap();
+        assert!(reader
+            .extract_all(&base, &ExtractOptions::default())
+            .unwrap()
+            .is_ok());
+
+        let options = CompareOptions::builder().contents(true).build();
+        let diff = reader.compare_dir(&base, &options).unwrap();
+        assert!(diff.is_empty(), "{diff:?}");
+
+        std::fs::remove_file(base.join("LICENSE")).unwrap();
+        std::fs::write(base.join("extra.txt"), "extra").unwrap();
+        std::fs::write(base.join("README.md"), "changed").unwrap();
+
+        let lib = std::fs::read(base.join("src/lib.rs")).unwrap();
+        let mut changed = lib.clone();
+        changed[0] ^= 0xff;
+        std::fs::write(base.join("src/lib.rs"), changed).unwrap();
+
+        let diff = reader.compare_dir(&base, &options).unwrap();
+        dbg!(&diff);
+
+        assert_eq!(diff.removed, [Path::new("LICENSE")]);
+        assert_eq!(diff.added, [Path::new("extra.txt")]);
+
+        assert!(matches!(
+            modified_in(&diff, "README.md")[0],
+            Change::Size { new: Some(7), .. }
+        ));
+
+        // Writing the file also changed its modification time
+        assert!(modified_in(&diff, "src/lib.rs").contains(&Change::Mtime {
+            old: reader_mtime(&mut reader, "src/lib.rs"),
+            new: std::fs::metadata(base.join("src/lib.rs"))
+                .unwrap()
+                .modified()
+                .ok(),
+        }));
+
+        let metadata_only = CompareOptions::builder().mtime(false).build();
+        let diff = reader.compare_dir(&base, &metadata_only).unwrap();
+        assert!(diff
+            .modified
+            .iter()
+            .all(|modified| modified.path != Path::new("src/lib.rs")));
+
+        let contents = CompareOptions::builder()
+            .mtime(false)
+            .contents(true)
+            .build();
+        let diff = reader.compare_dir(&base, &contents).unwrap();
+        assert!(diff.modified.contains(&Modified {
+            path: PathBuf::from("src/lib.rs"),
+            changes: vec![Change::Contents],
+        }));
+    }
+
+    #[test]
+    fn diff_archives() {
+        let base = std::env::temp_dir().join("archive-diff");
+        let _ = std::fs::remove_dir_all(&base);
+
+        for version in ["v1", "v2"] {
+            std::fs::create_dir_all(base.join(version).join("sub")).unwrap();
+            std::fs::write(base.join(version).join("same.txt"), "same").unwrap();
+        }
+
+        std::fs::write(base.join("v1/changed.txt"), "old").unwrap();
+        std::fs::write(base.join("v2/changed.txt"), "new").unwrap();
+        std::fs::write(base.join("v1/sub/removed.txt"), "removed").unwrap();
+        std::fs::write(base.join("v2/sub/added.txt"), "added").unwrap();
+
+        let archive = |name: &str, version: &str, order: &[&str]| {
+            let path = base.join(name);
+            let mut writer = ArchiveWriter::builder().path(&path).open().unwrap();
+
+            for name in order {
+                writer
+                    .add_path(
+                        base.join(version).join(name),
+                        &AddPathOptions::builder().name(*name).build(),
+                    )
+                    .unwrap();
+            }
+
+            writer.finish().unwrap();
+            path
+        };
+
+        let old = archive(
+            "release-1.tar.gz",
+            "v1",
+            &["same.txt", "changed.txt", "sub"],
+        );
+        let new = archive("release-2.zip", "v2", &["sub", "changed.txt", "same.txt"]);
+
+        let mut old = ArchiveReader::builder().source(old).open().unwrap();
+        let mut new = ArchiveReader::builder().source(new).open().unwrap();
+
+        let options = CompareOptions::builder()
+            .contents(true)
+            .mtime(false)
+            .owner(false)
+            .build();
+        let diff = old.diff(&mut new, &options).unwrap();
+        dbg!(&diff);
+
+        assert!(diff.errors.is_empty());
+        assert_eq!(diff.removed, [Path::new("sub/removed.txt")]);
+        assert_eq!(diff.added, [Path::new("sub/added.txt")]);
+        assert_eq!(
+            diff.modified,
+            [Modified {
+                path: PathBuf::from("changed.txt"),
+                changes: vec![Change::Contents],
+            }]
+        );
+
+        let metadata_only = CompareOptions::builder().mtime(false).owner(false).build();
+        assert!(old
+            .diff(&mut new, &metadata_only)
+            .unwrap()
+            .modified
+            .is_empty());
+
+        let mut same = ArchiveReader::builder()
+            .source(base.join("release-2.zip"))
+            .open()
+            .unwrap();
+        assert!(new.diff(&mut same, &options).unwrap().is_empty());
+    }
+
+    fn modified_in<'a>(diff: &'a Diff, path: &str) -> &'a [Change] {
+        &diff
+            .modified
+            .iter()
+            .find(|modified| modified.path == Path::new(path))
+            .unwrap()
+            .changes
+    }
+
+    fn reader_mtime(reader: &mut ArchiveReader, path: &str) -> Option<SystemTime> {
+        reader
+            .list()
+            .unwrap()
+            .into_iter()
+            .find(|entry| entry.path == Path::new(path))
+            .unwrap()
+            .mtime
+    }
+
+    #[test]
+    fn diff_unreadable() {
+        let opts = crate::ArchiveOptions::builder()
+            .formats([crate::core::ArchiveFormat::Zip])
+            .build();
+
+        let mut writer = ArchiveWriter::memory().handle_opts(opts).open().unwrap();
+        writer.add_bytes("a.txt", b"first").unwrap();
+        writer.add_bytes("b.txt", b"second").unwrap();
+        let intact = writer.finish_into_vec().unwrap();
+
+        // Corrupt the CRC-32 recorded in the central directory
+        // for `a.txt`, so that its data fails to read
+        let mut corrupt = intact.clone();
+        let offset = corrupt
+            .windows(4)
+            .position(|window| window == b"PK\x01\x02")
+            .unwrap();
+        corrupt[offset + 16] ^= 0xff;
+
+        let mut old = ArchiveReader::builder().source(intact).open().unwrap();
+        let mut new = ArchiveReader::builder().source(corrupt).open().unwrap();
+
+        let options = CompareOptions::builder().contents(true).build();
+        let diff = old.diff(&mut new, &options).unwrap();
+        dbg!(&diff);
+
+        assert!(diff.removed.is_empty());
+        assert!(diff.added.is_empty());
+        assert!(diff.modified.is_empty());
+        assert_eq!(diff.errors.len(), 1);
+        assert_eq!(diff.errors[0].path.as_deref(), Some(Path::new("a.txt")));
+    }
+}
@@ -0,0 +1,492 @@
+use std::ffi::{c_char, c_void, CStr, CString};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use archive_sys::archive_entry;
+use bon::Builder;
+
+use crate::error::{Error, Result};
+
+/// The type of an archive entry
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(rename_all = "kebab-case")
+)]
+pub enum EntryKind {
+    File,
+    Directory,
+    Symlink,
+    /// A link to an entry stored earlier in the
+    /// archive (see [`EntryInfo::hardlink`])
+    Hardlink,
+    CharDevice,
+    BlockDevice,
+    Fifo,
+    Socket,
+    /// A file type `libarchive` reported, but which
+    /// is not known to this crate
+    Unknown,
+}
+
+impl EntryKind {
+    /// Maps the file type bits of a mode onto an
+    /// [`EntryKind`]
+    pub(crate) fn from_mode(mode: u32) -> Self {
+        match mode & archive_sys::S_IFMT {
+            archive_sys::S_IFREG => Self::File,
+            archive_sys::S_IFDIR => Self::Directory,
+            archive_sys::S_IFLNK => Self::Symlink,
+            archive_sys::S_IFCHR => Self::CharDevice,
+            archive_sys::S_IFBLK => Self::BlockDevice,
+            archive_sys::S_IFIFO => Self::Fifo,
+            archive_sys::S_IFSOCK => Self::Socket,
+            _ => Self::Unknown,
+        }
+    }
+
+    /// The file type bits of a mode for this kind, or
+    /// `None` for kinds that have none of their own
+    fn file_type(self) -> Option<u32> {
+        match self {
+            // Hardlinks are regular files that point
+            // to another entry
+            Self::File | Self::Hardlink => Some(archive_sys::S_IFREG),
+            Self::Directory => Some(archive_sys::S_IFDIR),
+            Self::Symlink => Some(archive_sys::S_IFLNK),
+            Self::CharDevice => Some(archive_sys::S_IFCHR),
+            Self::BlockDevice => Some(archive_sys::S_IFBLK),
+            Self::Fifo => Some(archive_sys::S_IFIFO),
+            Self::Socket => Some(archive_sys::S_IFSOCK),
+            Self::Unknown => None,
+        }
+    }
+}
+
+/// The header of an entry to be written with
+/// [`crate::ArchiveWriter::add_entry`].
+///
+/// Only the path is required. Unset permissions default
+/// to `0o644` for files, `0o755` for directories and
+/// `0o777` for symlinks, and unset timestamps are not
+/// recorded (most formats then store the epoch).
+///
+/// ```
+/// use archive::entry::{EntryHeader, EntryKind};
+///
+/// let header = EntryHeader::builder()
+///     .path("bin/run.sh")
+///     .mode(0o755)
+///     .size(42)
+///     .uname("app")
+///     .mtime(std::time::SystemTime::now())
+///     .build();
+///
+/// let link = EntryHeader::builder()
+///     .path("bin/run")
+///     .kind(EntryKind::Symlink)
+///     .symlink("run.sh")
+///     .build();
+/// ```
+#[derive(Builder, Debug, Clone)]
+pub struct EntryHeader {
+    #[builder(into)]
+    /// The path of the entry within the archive
+    pub(crate) path: PathBuf,
+
+    #[builder(default = EntryKind::File)]
+    pub(crate) kind: EntryKind,
+
+    /// The permission bits of the entry
+    pub(crate) mode: Option<u32>,
+
+    /// The size of the entry's data. Required for
+    /// regular files
+    pub(crate) size: Option<u64>,
+
+    #[builder(default)]
+    pub(crate) uid: u64,
+    #[builder(default)]
+    pub(crate) gid: u64,
+    #[builder(into)]
+    pub(crate) uname: Option<String>,
+    #[builder(into)]
+    pub(crate) gname: Option<String>,
+
+    pub(crate) mtime: Option<SystemTime>,
+    pub(crate) atime: Option<SystemTime>,
+    pub(crate) ctime: Option<SystemTime>,
+    pub(crate) birthtime: Option<SystemTime>,
+
+    #[builder(into)]
+    /// Where a [`EntryKind::Symlink`] points to
+    pub(crate) symlink: Option<PathBuf>,
+    #[builder(into)]
+    /// The path of the entry a [`EntryKind::Hardlink`]
+    /// refers to, which must have been written before
+    pub(crate) hardlink: Option<PathBuf>,
+}
+
+impl EntryHeader {
+    /// The path of the entry within the archive
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The declared size of the entry's data, if any
+    pub fn size(&self) -> Option<u64> {
+        self.size
+    }
+
+    /// Builds a `libarchive` entry out of this header
+    pub(crate) fn to_raw(&self) -> Result<OwnedEntry> {
+        let Some(file_type) = self.kind.file_type() else {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("cannot write `{}` of an unknown kind", self.path.display()),
+            )
+            .into());
+        };
+
+        let mode = self.mode.unwrap_or(match self.kind {
+            EntryKind::Directory => 0o755,
+            EntryKind::Symlink => 0o777,
+            _ => 0o644,
+        });
+
+        let entry = OwnedEntry::new()?;
+        let path = CString::new(self.path.as_os_str().as_encoded_bytes())?;
+
+        unsafe {
+            archive_sys::archive_entry_copy_pathname(entry.0, path.as_ptr());
+            archive_sys::archive_entry_set_filetype(entry.0, file_type);
+            archive_sys::archive_entry_set_perm(entry.0, mode & 0o7777);
+            archive_sys::archive_entry_set_uid(entry.0, self.uid as i64);
+            archive_sys::archive_entry_set_gid(entry.0, self.gid as i64);
+
+            match (self.kind, self.size) {
+                // Hardlinks carry no data of their own
+                (EntryKind::Hardlink, _) => archive_sys::archive_entry_set_size(entry.0, 0),
+                (_, Some(size)) => archive_sys::archive_entry_set_size(entry.0, size as i64),
+                (EntryKind::File, None) => archive_sys::archive_entry_unset_size(entry.0),
+                // Only regular files have data, but some formats
+                // (e.g. cpio) need a size for every entry
+                (_, None) => archive_sys::archive_entry_set_size(entry.0, 0),
+            }
+
+            if let Some(uname) = &self.uname {
+                let uname = CString::new(uname.as_bytes())?;
+                archive_sys::archive_entry_copy_uname(entry.0, uname.as_ptr());
+            }
+
+            if let Some(gname) = &self.gname {
+                let gname = CString::new(gname.as_bytes())?;
+                archive_sys::archive_entry_copy_gname(entry.0, gname.as_ptr());
+            }
+
+            if let Some((sec, nsec)) = self.mtime.map(split_time) {
+                archive_sys::archive_entry_set_mtime(entry.0, sec as _, nsec as _);
+            }
+
+            if let Some((sec, nsec)) = self.atime.map(split_time) {
+                archive_sys::archive_entry_set_atime(entry.0, sec as _, nsec as _);
+            }
+
+            if let Some((sec, nsec)) = self.ctime.map(split_time) {
+                archive_sys::archive_entry_set_ctime(entry.0, sec as _, nsec as _);
+            }
+
+            if let Some((sec, nsec)) = self.birthtime.map(split_time) {
+                archive_sys::archive_entry_set_birthtime(entry.0, sec as _, nsec as _);
+            }
+
+            if let Some(symlink) = &self.symlink {
+                let symlink = CString::new(symlink.as_os_str().as_encoded_bytes())?;
+                archive_sys::archive_entry_copy_symlink(entry.0, symlink.as_ptr());
+            }
+
+            if let Some(hardlink) = &self.hardlink {
+                let hardlink = CString::new(hardlink.as_os_str().as_encoded_bytes())?;
+                archive_sys::archive_entry_copy_hardlink(entry.0, hardlink.as_ptr());
+            }
+        }
+
+        Ok(entry)
+    }
+}
+
+/// An owned snapshot of an entry's header, which outlives
+/// both the entry and the reader it came from.
+///
+/// Produced by [`crate::reader::ArchiveEntry::to_info`]
+/// and [`crate::ArchiveReader::list`]. Values that the
+/// archive format does not record are `None`.
+///
+/// With the `serde` feature, timestamps are (de)serialized
+/// as RFC 3339 strings.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[non_exhaustive]
+pub struct EntryInfo {
+    /// The path of the entry within the archive
+    pub path: PathBuf,
+    pub kind: EntryKind,
+    /// The size of the entry's data
+    pub size: Option<u64>,
+    /// The permission bits of the entry, without
+    /// its file type
+    pub mode: u32,
+
+    pub uid: u64,
+    pub gid: u64,
+    pub uname: Option<String>,
+    pub gname: Option<String>,
+
+    #[cfg_attr(feature = "serde", serde(with = "crate::rfc3339"))]
+    pub mtime: Option<SystemTime>,
+    #[cfg_attr(feature = "serde", serde(with = "crate::rfc3339"))]
+    pub atime: Option<SystemTime>,
+    #[cfg_attr(feature = "serde", serde(with = "crate::rfc3339"))]
+    pub ctime: Option<SystemTime>,
+    #[cfg_attr(feature = "serde", serde(with = "crate::rfc3339"))]
+    pub birthtime: Option<SystemTime>,
+
+    pub nlink: u32,
+    pub dev: Option<u64>,
+    pub ino: Option<u64>,
+    /// The device number of device entries
+    pub rdev: u64,
+    /// File flags, in the textual form used by
+    /// `chflags(1)`
+    pub fflags: Option<String>,
+
+    /// Where a symlink points to
+    pub symlink: Option<PathBuf>,
+    /// The path of the entry a hardlink refers to
+    pub hardlink: Option<PathBuf>,
+    /// Extended attributes, as name and value pairs
+    pub xattrs: Vec<(String, Vec<u8>)>,
+    /// Whether the entry's data or metadata is
+    /// encrypted
+    pub encrypted: bool,
+}
+
+impl EntryInfo {
+    /// Copies everything out of `entry`, whose path was
+    /// already decoded into `path`.
+    ///
+    /// # Safety
+    ///
+    /// `entry` must point to a valid entry. Its extended
+    /// attribute iterator is reset.
+    pub(crate) unsafe fn from_raw(entry: *mut archive_entry, path: PathBuf) -> Self {
+        unsafe {
+            let hardlink = path_of(archive_sys::archive_entry_hardlink(entry));
+            let kind = if hardlink.is_some() {
+                EntryKind::Hardlink
+            } else {
+                EntryKind::from_mode(archive_sys::archive_entry_filetype(entry) as u32)
+            };
+
+            Self {
+                path,
+                kind,
+                size: (archive_sys::archive_entry_size_is_set(entry) != 0)
+                    .then(|| archive_sys::archive_entry_size(entry).max(0) as u64),
+                mode: archive_sys::archive_entry_perm(entry) as u32,
+
+                uid: archive_sys::archive_entry_uid(entry) as u64,
+                gid: archive_sys::archive_entry_gid(entry) as u64,
+                uname: string_of(archive_sys::archive_entry_uname(entry)),
+                gname: string_of(archive_sys::archive_entry_gname(entry)),
+
+                mtime: (archive_sys::archive_entry_mtime_is_set(entry) != 0).then(|| {
+                    time_of(
+                        archive_sys::archive_entry_mtime(entry) as i64,
+                        archive_sys::archive_entry_mtime_nsec(entry) as i64,
+                    )
+                }),
+                atime: (archive_sys::archive_entry_atime_is_set(entry) != 0).then(|| {
+                    time_of(
+                        archive_sys::archive_entry_atime(entry) as i64,
+                        archive_sys::archive_entry_atime_nsec(entry) as i64,
+                    )
+                }),
+                ctime: (archive_sys::archive_entry_ctime_is_set(entry) != 0).then(|| {
+                    time_of(
+                        archive_sys::archive_entry_ctime(entry) as i64,
+                        archive_sys::archive_entry_ctime_nsec(entry) as i64,
+                    )
+                }),
+                birthtime: (archive_sys::archive_entry_birthtime_is_set(entry) != 0).then(|| {
+                    time_of(
+                        archive_sys::archive_entry_birthtime(entry) as i64,
+                        archive_sys::archive_entry_birthtime_nsec(entry) as i64,
+                    )
+                }),
+
+                nlink: archive_sys::archive_entry_nlink(entry) as u32,
+                dev: (archive_sys::archive_entry_dev_is_set(entry) != 0)
+                    .then(|| archive_sys::archive_entry_dev(entry) as u64),
+                ino: (archive_sys::archive_entry_ino_is_set(entry) != 0)
+                    .then(|| archive_sys::archive_entry_ino(entry) as u64),
+                rdev: archive_sys::archive_entry_rdev(entry) as u64,
+                fflags: string_of(archive_sys::archive_entry_fflags_text(entry)),
+
+                symlink: path_of(archive_sys::archive_entry_symlink(entry)),
+                hardlink,
+                xattrs: xattrs_of(entry),
+                encrypted: archive_sys::archive_entry_is_encrypted(entry) != 0,
+            }
+        }
+    }
+}
+
+/// An entry owned by this crate rather than by a
+/// handle, freed on drop
+pub(crate) struct OwnedEntry(pub(crate) *mut archive_entry);
+
+impl OwnedEntry {
+    /// Allocates a blank entry
+    pub(crate) fn new() -> Result<Self> {
+        let entry = unsafe { archive_sys::archive_entry_new() };
+
+        if entry.is_null() {
+            return Err(Error::Initialization);
+        }
+
+        Ok(Self(entry))
+    }
+}
+
+impl Drop for OwnedEntry {
+    fn drop(&mut self) {
+        if !self.0.is_null() {
+            unsafe { archive_sys::archive_entry_free(self.0) };
+        }
+    }
+}
+
+/// Copies a string owned by an entry, if it is set
+unsafe fn string_of(raw: *const c_char) -> Option<String> {
+    if raw.is_null() {
+        return None;
+    }
+
+    Some(
+        unsafe { CStr::from_ptr(raw) }
+            .to_string_lossy()
+            .into_owned(),
+    )
+}
+
+pub(crate) unsafe fn path_of(raw: *const c_char) -> Option<PathBuf> {
+    unsafe { string_of(raw) }.map(PathBuf::from)
+}
+
+/// Converts a timestamp relative to the epoch, which
+/// may be negative
+fn time_of(sec: i64, nsec: i64) -> SystemTime {
+    let nsec = Duration::from_nanos(nsec.clamp(0, 999_999_999) as u64);
+
+    if sec >= 0 {
+        UNIX_EPOCH + Duration::from_secs(sec as u64) + nsec
+    } else {
+        UNIX_EPOCH - Duration::from_secs(sec.unsigned_abs()) + nsec
+    }
+}
+
+/// Splits a timestamp into seconds relative to the epoch
+/// and nanoseconds, the inverse of [`time_of`]
+fn split_time(time: SystemTime) -> (i64, i64) {
+    match time.duration_since(UNIX_EPOCH) {
+        Ok(after) => (after.as_secs() as i64, after.subsec_nanos() as i64),
+        Err(before) => {
+            let before = before.duration();
+            let sec = -(before.as_secs() as i64);
+
+            match before.subsec_nanos() {
+                0 => (sec, 0),
+                nsec => (sec - 1, 1_000_000_000 - nsec as i64),
+            }
+        }
+    }
+}
+
+unsafe fn xattrs_of(entry: *mut archive_entry) -> Vec<(String, Vec<u8>)> {
+    let mut xattrs = Vec::new();
+
+    unsafe {
+        archive_sys::archive_entry_xattr_reset(entry);
+
+        loop {
+            let mut name: *const c_char = std::ptr::null();
+            let mut value: *const c_void = std::ptr::null();
+            let mut size = 0;
+
+            let ret =
+                archive_sys::archive_entry_xattr_next(entry, &mut name, &mut value, &mut size);
+
+            if ret != archive_sys::ARCHIVE_OK as i32 {
+                break;
+            }
+
+            let Some(name) = string_of(name) else {
+                continue;
+            };
+
+            let value = if value.is_null() {
+                Vec::new()
+            } else {
+                std::slice::from_raw_parts(value as *const u8, size).to_vec()
+            };
+
+            xattrs.push((name, value));
+        }
+    }
+
+    xattrs
+}
+
+#[cfg(all(test, feature = "serde"))]
+mod tests {
+    use super::*;
+    use crate::core::{ArchiveFilter, ArchiveFormat};
+    use crate::{ArchiveOptions, ArchiveReader, ArchiveWriter};
+
+    #[test]
+    fn listing() {
+        let header = EntryHeader::builder()
+            .path("LICENSE")
+            .size(3)
+            .mtime(UNIX_EPOCH + Duration::from_secs(1_700_000_000))
+            .build();
+
+        let options = ArchiveOptions::builder()
+            .filters([ArchiveFilter::None])
+            .formats([ArchiveFormat::TarPaxRestricted])
+            .build();
+        let mut writer = ArchiveWriter::memory().handle_opts(options).open().unwrap();
+        writer.add_entry(&header, &b"MIT"[..]).unwrap();
+
+        let mut reader = ArchiveReader::builder()
+            .source(writer.finish_into_vec().unwrap())
+            .open()
+            .unwrap();
+        let entries = reader.list().unwrap();
+
+        let json = serde_json::to_value(&entries).unwrap();
+        assert_eq!(json[0]["mtime"], "2023-11-14T22:13:20Z");
+        assert_eq!(json[0]["atime"], serde_json::Value::Null);
+
+        let parsed: Vec<EntryInfo> = serde_json::from_value(json).unwrap();
+        assert_eq!(parsed, entries);
+
+        reader.rewind().unwrap();
+        let entry = reader.next_entry().unwrap().unwrap();
+        let metadata = serde_json::to_value(entry.metadata()).unwrap();
+        assert!(metadata["mode"].is_u64());
+        assert_eq!(metadata["size"], 3);
+    }
+}
@@ -1,18 +1,182 @@
+use std::io;
+use std::path::PathBuf;
+
 use thiserror::Error;
 
+use crate::core::{ArchiveFilter, ArchiveFormat};
+
 pub(crate) type Result<T> = std::result::Result<T, Error>;
 
+/// The status reported by a `libarchive` function that
+/// did not return `ARCHIVE_OK`
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Status {
+    /// The end of the archive was reached (`ARCHIVE_EOF`)
+    Eof,
+    /// The operation may succeed if it is retried
+    /// (`ARCHIVE_RETRY`)
+    Retry,
+    /// The operation partially succeeded (`ARCHIVE_WARN`)
+    Warn,
+    /// The current operation cannot complete, but the
+    /// handle is still usable (`ARCHIVE_FAILED`)
+    Failed,
+    /// No more operations are possible on the handle
+    /// (`ARCHIVE_FATAL`)
+    Fatal,
+}
+
+impl Status {
+    /// Maps a `libarchive` return code onto a [`Status`].
+    /// Returns `None` for `ARCHIVE_OK`.
+    pub(crate) fn from_code(code: i32) -> Option<Self> {
+        match code {
+            code if code == archive_sys::ARCHIVE_OK as i32 => None,
+            code if code == archive_sys::ARCHIVE_EOF as i32 => Some(Self::Eof),
+            archive_sys::ARCHIVE_RETRY => Some(Self::Retry),
+            archive_sys::ARCHIVE_WARN => Some(Self::Warn),
+            archive_sys::ARCHIVE_FAILED => Some(Self::Failed),
+            _ => Some(Self::Fatal),
+        }
+    }
+}
+
 #[derive(Debug, Error)]
 pub enum Error {
-    #[error("libarchive error: {message} (error {code})")]
-    Archive { message: String, code: i32 },
+    #[error(
+        "libarchive error in {operation}: {message} ({status:?}){}",
+        .path.as_ref().map(|path| format!(" at entry `{}`", path.display())).unwrap_or_default()
+    )]
+    Archive {
+        /// The status returned by `libarchive`
+        status: Status,
+        /// The value of `archive_errno(3)`
+        errno: i32,
+        /// `errno` mapped onto an [`io::ErrorKind`]
+        kind: io::ErrorKind,
+        /// The value of `archive_error_string(3)`
+        message: String,
+        /// The `libarchive` function that failed
+        operation: &'static str,
+        /// The path of the entry being processed,
+        /// if any
+        path: Option<PathBuf>,
+    },
+
+    #[error("I/O error: {0}")]
+    Io(#[from] io::Error),
 
     #[error("Failed to initialize a read or write handle")]
     Initialization,
 
-    #[error("Archive cannot be opened more than once")]
-    AlreadyOpen,
+    #[error("archive format {0:?} is not in the allowed set of formats")]
+    FormatNotAllowed(ArchiveFormat),
+
+    #[error("compression filter {0:?} is not in the allowed set of filters")]
+    FilterNotAllowed(ArchiveFilter),
+
+    #[error("Writers only accept a single filter and format")]
+    AmbiguousOptions,
+
+    #[error("compression filter {0:?} cannot be enabled through ArchiveOptions")]
+    UnsupportedFilter(ArchiveFilter),
+
+    #[error("cannot pick an archive format from the name of `{}`", .0.display())]
+    UnknownExtension(PathBuf),
+
+    #[error("a format is needed for archives not written to a file")]
+    FormatRequired,
+
+    #[error("refusing to extract `{}` outside of the destination", .0.display())]
+    UnsafePath(PathBuf),
 
     #[error("internal null byte included: {0}")]
     NullString(#[from] std::ffi::NulError),
 }
+
+impl Error {
+    /// Builds an [`Error::Archive`] from the error state of
+    /// `handle` after `operation` returned `code`
+    pub(crate) fn from_handle(
+        handle: *mut archive_sys::archive,
+        code: i32,
+        operation: &'static str,
+    ) -> Self {
+        let errno = unsafe { archive_sys::archive_errno(handle) };
+
+        Self::Archive {
+            status: Status::from_code(code).unwrap_or(Status::Fatal),
+            errno,
+            kind: errno_kind(errno),
+            message: crate::get_error(handle, code).to_string(),
+            operation,
+            path: None,
+        }
+    }
+
+    /// Attaches the path of the entry that was being
+    /// processed when this error occurred
+    pub(crate) fn with_path(mut self, entry_path: impl Into<PathBuf>) -> Self {
+        if let Self::Archive { path, .. } = &mut self {
+            *path = Some(entry_path.into());
+        }
+
+        self
+    }
+
+    /// The `libarchive` status behind this error, if
+    /// it came from `libarchive`
+    pub fn status(&self) -> Option<Status> {
+        match self {
+            Self::Archive { status, .. } => Some(*status),
+            _ => None,
+        }
+    }
+
+    /// Whether the handle this error came from can no
+    /// longer be used
+    pub fn is_fatal(&self) -> bool {
+        !matches!(
+            self.status(),
+            Some(Status::Retry | Status::Warn | Status::Failed)
+        )
+    }
+
+    /// Categorizes this error as an [`io::ErrorKind`]
+    pub fn kind(&self) -> io::ErrorKind {
+        match self {
+            Self::Archive { kind, .. } => *kind,
+            Self::Io(e) => e.kind(),
+            Self::FormatNotAllowed(_) | Self::FilterNotAllowed(_) | Self::UnsafePath(_) => {
+                io::ErrorKind::InvalidData
+            }
+            Self::AmbiguousOptions
+            | Self::UnsupportedFilter(_)
+            | Self::UnknownExtension(_)
+            | Self::FormatRequired
+            | Self::NullString(_) => io::ErrorKind::InvalidInput,
+            Self::Initialization => io::ErrorKind::Other,
+        }
+    }
+}
+
+impl From<Error> for io::Error {
+    fn from(value: Error) -> Self {
+        match value {
+            Error::Io(e) => e,
+            e => io::Error::new(e.kind(), e),
+        }
+    }
+}
+
+/// Maps the value of `archive_errno(3)` onto an
+/// [`io::ErrorKind`]. `libarchive` reports its own
+/// errors (e.g. malformed archives) with
+/// `ARCHIVE_ERRNO_MISC`, which is negative.
+fn errno_kind(errno: i32) -> io::ErrorKind {
+    if errno <= 0 {
+        io::ErrorKind::Other
+    } else {
+        io::Error::from_raw_os_error(errno).kind()
+    }
+}
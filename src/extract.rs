@@ -0,0 +1,719 @@
+use std::ffi::CString;
+use std::fs::create_dir_all;
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use archive_sys::archive;
+
+use bon::Builder;
+use log::{debug, error, warn};
+
+use crate::entry::OwnedEntry;
+use crate::error::{Error, Result, Status};
+use crate::reader::{ArchiveEntry, ArchiveReader};
+
+/// Flags handed to `archive_write_disk_set_options(3)`.
+///
+/// Besides restoring permissions and modification times,
+/// these make `libarchive` refuse to write through symlinks
+/// and to follow `..`, on top of the checks done by
+/// [`DiskWriter`] itself
+const DISK_FLAGS: u32 = archive_sys::ARCHIVE_EXTRACT_TIME
+    | archive_sys::ARCHIVE_EXTRACT_PERM
+    | archive_sys::ARCHIVE_EXTRACT_SECURE_SYMLINKS
+    | archive_sys::ARCHIVE_EXTRACT_SECURE_NODOTDOT;
+
+/// A callback that is invoked after every extracted entry
+pub type ProgressFn = Arc<dyn Fn(&Progress) + Send + Sync>;
+
+/// Options for [`ArchiveReader::extract_all`]
+#[derive(Builder, Clone)]
+pub struct ExtractOptions {
+    #[builder(default = 1)]
+    /// The number of threads extracting the archive.
+    ///
+    /// With more than one thread, the archive is
+    /// indexed once, then every thread opens its own
+    /// handle on the same source and extracts a
+    /// disjoint subset of its regular files. This pays
+    /// off for formats that can seek to an entry (zip,
+    /// 7z) or hold many large files, as every handle
+    /// still has to skip over the entries it does not
+    /// extract.
+    ///
+    /// Only files and in-memory archives can be opened
+    /// more than once. Neither
+    /// [`crate::ArchiveSource::Reader`] nor
+    /// [`crate::ArchiveSource::Stream`] can be reopened,
+    /// so both fall back to a single thread. Defaults to 1
+    pub(crate) threads: usize,
+
+    #[builder(with = |progress: impl Fn(&Progress) + Send + Sync + 'static| Arc::new(progress) as ProgressFn)]
+    /// Called after each entry is extracted. With more
+    /// than one thread, this is called from the
+    /// extracting threads, in no particular order
+    pub(crate) progress: Option<ProgressFn>,
+}
+
+impl Default for ExtractOptions {
+    fn default() -> Self {
+        Self::builder().build()
+    }
+}
+
+/// The state of an extraction, as reported to
+/// [`ExtractOptions`]'s `progress` callback
+#[derive(Debug)]
+pub struct Progress<'a> {
+    /// The path of the entry that was just extracted,
+    /// relative to the destination
+    pub path: &'a Path,
+    /// The number of entries extracted so far
+    pub entries: usize,
+    /// The number of bytes extracted so far
+    pub bytes: u64,
+    /// The number of entries in the archive, when
+    /// it was indexed beforehand
+    pub total_entries: Option<usize>,
+    /// The size of the regular files in the archive,
+    /// when it was indexed beforehand
+    pub total_bytes: Option<u64>,
+}
+
+/// The outcome of [`ArchiveReader::extract_all`]
+#[derive(Debug, Default)]
+pub struct ExtractReport {
+    /// The number of entries that were extracted
+    pub entries: usize,
+    /// The number of bytes that were written
+    pub bytes: u64,
+    /// The entries that could not be extracted
+    pub errors: Vec<EntryError>,
+}
+
+impl ExtractReport {
+    /// Whether every entry was extracted
+    pub fn is_ok(&self) -> bool {
+        self.errors.is_empty()
+    }
+
+    fn merge(&mut self, other: ExtractReport) {
+        self.entries += other.entries;
+        self.bytes += other.bytes;
+        self.errors.extend(other.errors);
+    }
+}
+
+/// An error that occurred while extracting an archive
+#[derive(Debug)]
+pub struct EntryError {
+    /// The path of the entry within the archive, or
+    /// `None` when its header could not be read
+    pub path: Option<PathBuf>,
+    pub error: Error,
+}
+
+/// Extracts the archive read by `reader` onto `dest`,
+/// splitting the work across threads when the
+/// options and the source allow for it
+pub(crate) fn extract_all(
+    reader: &mut ArchiveReader,
+    dest: &Path,
+    options: &ExtractOptions,
+) -> Result<ExtractReport> {
+    reader.rewind()?;
+
+    if options.threads > 1 {
+        if reader.input.try_clone().is_some() {
+            return extract_parallel(reader, dest, options);
+        }
+
+        debug!("Source cannot be reopened, extracting on a single thread");
+    }
+
+    let tracker = Tracker::new(options, None, None);
+    let mut disk = DiskWriter::new(dest, reader.chunk_size)?;
+
+    let mut report = run(reader, &mut disk, &tracker, |_| true, None, true);
+    if let Err(error) = disk.close() {
+        report.errors.push(EntryError { path: None, error });
+    }
+
+    Ok(report)
+}
+
+fn extract_parallel(
+    reader: &mut ArchiveReader,
+    dest: &Path,
+    options: &ExtractOptions,
+) -> Result<ExtractReport> {
+    let index = Index::build(reader);
+
+    // Only regular files are worth distributing. Directories
+    // and links are left to a final pass, so that link targets
+    // exist and directory times are restored last
+    let threads = options.threads.min(index.files.len()).max(1);
+    let mut owners = vec![None; index.entries];
+    let mut assigned = vec![0; threads];
+
+    let mut files = index.files.clone();
+    files.sort_by_key(|(_, size)| std::cmp::Reverse(*size));
+
+    for (ordinal, size) in files {
+        let (worker, _) = assigned
+            .iter()
+            .enumerate()
+            .min_by_key(|(_, bytes)| **bytes)
+            .unwrap();
+
+        owners[ordinal] = Some(worker);
+        assigned[worker] += size;
+    }
+
+    debug!(
+        "Extracting {} entries on {} threads ({:?} bytes each)",
+        index.entries, threads, assigned
+    );
+
+    let tracker = Tracker::new(
+        options,
+        Some(index.entries),
+        Some(index.files.iter().map(|(_, size)| size).sum()),
+    );
+    let last = index.entries.checked_sub(1);
+    let mut report = ExtractReport {
+        errors: index.errors,
+        ..Default::default()
+    };
+
+    // Creates the destination before the workers race for it
+    let mut disk = DiskWriter::new(dest, reader.chunk_size)?;
+
+    let reports: Vec<ExtractReport> = std::thread::scope(|scope| {
+        let workers: Vec<_> = (0..threads)
+            .map(|worker| {
+                let (owners, tracker) = (&owners, &tracker);
+                let input = reader.input.try_clone();
+                let chunk_size = reader.chunk_size;
+                let handle_opts = reader.handle_opts.clone();
+
+                scope.spawn(move || {
+                    let Some(last) = owners.iter().rposition(|owner| *owner == Some(worker)) else {
+                        return ExtractReport::default();
+                    };
+
+                    let opened = input
+                        .ok_or(Error::Initialization)
+                        .and_then(|input| ArchiveReader::from_input(input, chunk_size, handle_opts))
+                        .and_then(|reader| Ok((reader, DiskWriter::new(dest, chunk_size)?)));
+
+                    let (mut reader, mut disk) = match opened {
+                        Ok(opened) => opened,
+                        Err(error) => {
+                            error!("Failed to start extraction worker {}: {}", worker, error);
+
+                            return ExtractReport {
+                                errors: vec![EntryError { path: None, error }],
+                                ..Default::default()
+                            };
+                        }
+                    };
+
+                    let select = |ordinal| owners[ordinal] == Some(worker);
+                    let mut report =
+                        run(&mut reader, &mut disk, tracker, select, Some(last), false);
+
+                    if let Err(error) = disk.close() {
+                        report.errors.push(EntryError { path: None, error });
+                    }
+
+                    report
+                })
+            })
+            .collect();
+
+        workers
+            .into_iter()
+            .map(|worker| worker.join().expect("extraction worker panicked"))
+            .collect()
+    });
+
+    for worker in reports {
+        report.merge(worker);
+    }
+
+    if last.is_some() {
+        reader.rewind()?;
+        report.merge(run(
+            reader,
+            &mut disk,
+            &tracker,
+            |ordinal| owners[ordinal].is_none(),
+            last,
+            false,
+        ));
+    }
+
+    if let Err(error) = disk.close() {
+        report.errors.push(EntryError { path: None, error });
+    }
+
+    Ok(report)
+}
+
+/// Extracts the entries of `reader` whose ordinal is
+/// selected, up to and including the `last` one.
+///
+/// Errors are collected into the report, and only stop
+/// the extraction once a handle is no longer usable.
+/// Header errors are only reported when
+/// `header_errors` is set, as workers read the same
+/// headers as the index.
+fn run(
+    reader: &mut ArchiveReader,
+    disk: &mut DiskWriter,
+    tracker: &Tracker,
+    mut select: impl FnMut(usize) -> bool,
+    last: Option<usize>,
+    header_errors: bool,
+) -> ExtractReport {
+    let mut report = ExtractReport::default();
+    let mut ordinal = 0;
+
+    while last.is_none_or(|last| ordinal <= last) {
+        ordinal += 1;
+
+        let mut entry = match reader.next_entry() {
+            Ok(Some(entry)) => entry,
+            Ok(None) => break,
+            Err(error) => {
+                let fatal = is_fatal(&error);
+
+                if header_errors || fatal {
+                    report.errors.push(EntryError { path: None, error });
+                }
+
+                if fatal {
+                    break;
+                }
+
+                continue;
+            }
+        };
+
+        if !select(ordinal - 1) {
+            continue;
+        }
+
+        let path = entry.archive_path().to_path_buf();
+
+        match disk.write_entry(&mut entry) {
+            Ok(bytes) => {
+                report.entries += 1;
+                report.bytes += bytes;
+                tracker.record(&path, bytes);
+            }
+            Err(error) => {
+                error!("Failed to extract `{}`: {}", path.display(), error);
+                let fatal = is_fatal(&error);
+
+                report.errors.push(EntryError {
+                    path: Some(path),
+                    error,
+                });
+
+                if fatal {
+                    break;
+                }
+            }
+        }
+    }
+
+    report
+}
+
+/// Whether `error` left a handle unusable. Errors that
+/// do not come from `libarchive` (e.g. rejected paths)
+/// only concern the current entry
+pub(crate) fn is_fatal(error: &Error) -> bool {
+    error.status() == Some(Status::Fatal)
+}
+
+/// The entries of an archive, as read once before
+/// distributing them across threads
+struct Index {
+    /// The number of headers that were read, including
+    /// the ones that could not be read
+    entries: usize,
+    /// The ordinal and size of every regular file
+    files: Vec<(usize, u64)>,
+    errors: Vec<EntryError>,
+}
+
+impl Index {
+    fn build(reader: &mut ArchiveReader) -> Self {
+        let mut index = Self {
+            entries: 0,
+            files: Vec::new(),
+            errors: Vec::new(),
+        };
+
+        loop {
+            let entry = match reader.next_entry() {
+                Ok(Some(entry)) => entry,
+                Ok(None) => break,
+                Err(error) => {
+                    let fatal = is_fatal(&error);
+                    index.errors.push(EntryError { path: None, error });
+
+                    // Nothing past this point can be read,
+                    // by any handle
+                    if fatal {
+                        break;
+                    }
+
+                    index.entries += 1;
+                    continue;
+                }
+            };
+
+            let hardlink = unsafe { archive_sys::archive_entry_hardlink(entry.raw()) };
+
+            if entry.metadata().is_file() && hardlink.is_null() {
+                index
+                    .files
+                    .push((index.entries, entry.size().max(0) as u64));
+            }
+
+            index.entries += 1;
+        }
+
+        index
+    }
+}
+
+/// Progress shared by every extracting thread
+struct Tracker<'a> {
+    options: &'a ExtractOptions,
+    entries: AtomicUsize,
+    bytes: AtomicU64,
+    total_entries: Option<usize>,
+    total_bytes: Option<u64>,
+}
+
+impl<'a> Tracker<'a> {
+    fn new(
+        options: &'a ExtractOptions,
+        total_entries: Option<usize>,
+        total_bytes: Option<u64>,
+    ) -> Self {
+        Self {
+            options,
+            entries: AtomicUsize::new(0),
+            bytes: AtomicU64::new(0),
+            total_entries,
+            total_bytes,
+        }
+    }
+
+    fn record(&self, path: &Path, bytes: u64) {
+        let entries = self.entries.fetch_add(1, Ordering::Relaxed) + 1;
+        let bytes = self.bytes.fetch_add(bytes, Ordering::Relaxed) + bytes;
+
+        if let Some(progress) = &self.options.progress {
+            progress(&Progress {
+                path,
+                entries,
+                bytes,
+                total_entries: self.total_entries,
+                total_bytes: self.total_bytes,
+            });
+        }
+    }
+}
+
+/// A `libarchive` handle writing entries onto disk,
+/// below a destination directory.
+///
+/// Directory permissions and times are only restored
+/// once the writer is closed.
+pub(crate) struct DiskWriter {
+    handle: *mut archive,
+    dest: PathBuf,
+    buffer: Vec<u8>,
+}
+
+impl DiskWriter {
+    /// Creates `dest` if needed, and a handle writing
+    /// below it, copying data in chunks of `chunk_size`
+    pub(crate) fn new(dest: &Path, chunk_size: usize) -> Result<Self> {
+        create_dir_all(dest)?;
+
+        // `libarchive` refuses to write through symlinks, which
+        // includes any in the path leading to the destination
+        let dest = dest.canonicalize()?;
+        let handle = unsafe { archive_sys::archive_write_disk_new() };
+
+        if handle.is_null() {
+            return Err(Error::Initialization);
+        }
+
+        let writer = Self {
+            handle,
+            dest,
+            buffer: vec![0; chunk_size.max(1)],
+        };
+
+        let ret = unsafe { archive_sys::archive_write_disk_set_options(handle, DISK_FLAGS as i32) };
+        if ret != archive_sys::ARCHIVE_OK as i32 {
+            return Err(Error::from_handle(
+                handle,
+                ret,
+                "archive_write_disk_set_options",
+            ));
+        }
+
+        let ret = unsafe { archive_sys::archive_write_disk_set_standard_lookup(handle) };
+        if ret != archive_sys::ARCHIVE_OK as i32 {
+            return Err(Error::from_handle(
+                handle,
+                ret,
+                "archive_write_disk_set_standard_lookup",
+            ));
+        }
+
+        Ok(writer)
+    }
+
+    /// Writes `entry` and its data below the destination,
+    /// returning the number of bytes written
+    pub(crate) fn write_entry(&mut self, entry: &mut ArchiveEntry<'_>) -> Result<u64> {
+        let path = entry.archive_path().to_path_buf();
+        let target = self.resolve(&path)?;
+
+        debug!("Extracting `{}`", path.display());
+
+        let hardlink = unsafe { archive_sys::archive_entry_hardlink(entry.raw()) };
+        let hardlink = if hardlink.is_null() {
+            None
+        } else {
+            let hardlink = unsafe { std::ffi::CStr::from_ptr(hardlink) };
+            Some(self.resolve(Path::new(&*hardlink.to_string_lossy()))?)
+        };
+
+        // The entry belongs to the reader, so the copy is the
+        // one pointed at the destination
+        let disk_entry = OwnedEntry(unsafe { archive_sys::archive_entry_clone(entry.raw()) });
+        if disk_entry.0.is_null() {
+            return Err(Error::Initialization);
+        }
+
+        let target = CString::new(target.as_os_str().as_encoded_bytes())?;
+        unsafe { archive_sys::archive_entry_copy_pathname(disk_entry.0, target.as_ptr()) };
+
+        if let Some(hardlink) = hardlink {
+            let hardlink = CString::new(hardlink.as_os_str().as_encoded_bytes())?;
+            unsafe { archive_sys::archive_entry_copy_hardlink(disk_entry.0, hardlink.as_ptr()) };
+        }
+
+        let ret = unsafe { archive_sys::archive_write_header(self.handle, disk_entry.0) };
+        self.check(ret, "archive_write_header", &path)?;
+
+        let mut total = 0;
+
+        loop {
+            let read = entry.read_data(&mut self.buffer)?;
+
+            if read == 0 {
+                break;
+            }
+
+            let written = unsafe {
+                archive_sys::archive_write_data(
+                    self.handle,
+                    self.buffer.as_ptr() as *const std::ffi::c_void,
+                    read,
+                )
+            };
+
+            if written < 0 {
+                return Err(
+                    Error::from_handle(self.handle, written as i32, "archive_write_data")
+                        .with_path(&path),
+                );
+            }
+
+            total += read as u64;
+        }
+
+        let ret = unsafe { archive_sys::archive_write_finish_entry(self.handle) };
+        self.check(ret, "archive_write_finish_entry", &path)?;
+
+        if entry.metadata().is_file() && (total as i64) < entry.size() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                format!(
+                    "data of `{}` ended before the size recorded in its header",
+                    path.display()
+                ),
+            )
+            .into());
+        }
+
+        Ok(total)
+    }
+
+    /// Applies the pending directory permissions and
+    /// times, then frees the handle
+    pub(crate) fn close(mut self) -> Result<()> {
+        self.free()
+    }
+
+    /// Maps a path from the archive below the destination,
+    /// rejecting any path that could escape it
+    fn resolve(&self, path: &Path) -> Result<PathBuf> {
+        let escapes = path.components().any(|component| {
+            matches!(
+                component,
+                Component::RootDir | Component::Prefix(_) | Component::ParentDir
+            )
+        });
+
+        if escapes {
+            warn!("Rejecting unsafe entry path `{}`", path.display());
+            return Err(Error::UnsafePath(path.to_path_buf()));
+        }
+
+        Ok(self.dest.join(path))
+    }
+
+    /// Turns a `libarchive` result into an error for
+    /// `path`, logging warnings
+    fn check(&self, ret: i32, operation: &'static str, path: &Path) -> Result<()> {
+        if ret == archive_sys::ARCHIVE_WARN {
+            warn!(
+                "{} for `{}`: {}",
+                operation,
+                path.display(),
+                crate::get_error(self.handle, ret)
+            );
+        } else if ret != archive_sys::ARCHIVE_OK as i32 {
+            return Err(Error::from_handle(self.handle, ret, operation).with_path(path));
+        }
+
+        Ok(())
+    }
+
+    /// Closes and frees the handle, nulling it so that
+    /// it is only ever freed once.
+    fn free(&mut self) -> Result<()> {
+        let handle = std::mem::replace(&mut self.handle, std::ptr::null_mut());
+
+        if handle.is_null() {
+            return Ok(());
+        }
+
+        let ret = unsafe { archive_sys::archive_write_close(handle) };
+        let result = if ret != archive_sys::ARCHIVE_OK as i32 {
+            Err(Error::from_handle(handle, ret, "archive_write_close"))
+        } else {
+            Ok(())
+        };
+
+        let ret = unsafe { archive_sys::archive_write_free(handle) };
+
+        if ret != archive_sys::ARCHIVE_OK as i32 {
+            error!("archive_write_free failed with code {}", ret);
+        }
+
+        result
+    }
+}
+
+impl Drop for DiskWriter {
+    fn drop(&mut self) {
+        if let Err(e) = self.free() {
+            error!("Failed to close disk writer: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    /// Lists the regular files below `dir` with their contents
+    fn contents(dir: &Path) -> Vec<(PathBuf, Vec<u8>)> {
+        let mut files = Vec::new();
+        let mut pending = vec![dir.to_path_buf()];
+
+        while let Some(current) = pending.pop() {
+            for entry in std::fs::read_dir(current).unwrap() {
+                let path = entry.unwrap().path();
+
+                if path.is_dir() {
+                    pending.push(path);
+                } else {
+                    let data = std::fs::read(&path).unwrap();
+                    files.push((path.strip_prefix(dir).unwrap().to_path_buf(), data));
+                }
+            }
+        }
+
+        files.sort();
+        files
+    }
+
+    #[test]
+    fn extract_serial_and_parallel() {
+        let base = std::env::temp_dir().join("archive-extract");
+        let _ = std::fs::remove_dir_all(&base);
+
+        let mut reader = ArchiveReader::builder()
+            .source("archive.tar.gz")
+            .open()
+            .unwrap();
+
+        let serial = reader
+            .extract_all(base.join("serial"), &ExtractOptions::default())
+            .unwrap();
+        dbg!(&serial);
+
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let options = {
+            let seen = Arc::clone(&seen);
+
+            ExtractOptions::builder()
+                .threads(4)
+                .progress(move |progress| {
+                    assert_eq!(progress.total_entries, Some(serial.entries));
+                    seen.lock().unwrap().push(progress.path.to_path_buf());
+                })
+                .build()
+        };
+
+        let parallel = reader.extract_all(base.join("parallel"), &options).unwrap();
+        dbg!(&parallel);
+
+        assert!(serial.is_ok() && parallel.is_ok());
+        assert_eq!(serial.entries, parallel.entries);
+        assert_eq!(serial.bytes, parallel.bytes);
+        assert_eq!(seen.lock().unwrap().len(), parallel.entries);
+
+        let extracted = contents(&base.join("serial"));
+        assert!(!extracted.is_empty());
+        assert_eq!(extracted, contents(&base.join("parallel")));
+    }
+
+    #[test]
+    fn extract_rejects_unsafe_paths() {
+        for path in ["../escape.txt", "/escape.txt"] {
+            let disk = DiskWriter::new(&std::env::temp_dir(), 1024).unwrap();
+            let result = disk.resolve(Path::new(path));
+
+            assert!(matches!(result, Err(Error::UnsafePath(_))));
+        }
+    }
+}
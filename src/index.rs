@@ -0,0 +1,349 @@
+use std::collections::BTreeMap;
+use std::path::{Component, Path, PathBuf};
+
+use log::debug;
+
+use crate::core::ArchiveFormat;
+use crate::error::Result;
+use crate::reader::{ArchiveEntry, ArchiveReader, EntryMetadata};
+
+/// The headers of an archive, read once and looked up by
+/// path afterwards.
+///
+/// Paths are compared component by component, ignoring a
+/// leading `./` and trailing slashes. When an archive holds
+/// the same path more than once, the last entry wins, as it
+/// would when extracting.
+///
+/// ```no_run
+/// use std::io::Read;
+/// use archive::{ArchiveIndex, ArchiveReader};
+///
+/// let reader = ArchiveReader::builder().source("archive.tar").open()?;
+/// let mut index = ArchiveIndex::build(reader)?;
+///
+/// if let Some(mut entry) = index.open_entry("config/app.toml")? {
+///     let mut config = String::new();
+///     entry.read_to_string(&mut config)?;
+/// }
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+pub struct ArchiveIndex {
+    reader: ArchiveReader,
+    entries: Vec<IndexEntry>,
+    paths: BTreeMap<PathBuf, usize>,
+
+    /// The format to read entries as when reopening the
+    /// archive at their header, or `None` when entries
+    /// can only be reached by rescanning the archive
+    seek_format: Option<ArchiveFormat>,
+
+    /// The number of headers the reader went past since it
+    /// was last at the start of the archive, if known
+    cursor: Option<usize>,
+}
+
+/// An entry recorded in an [`ArchiveIndex`]
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct IndexEntry {
+    path: PathBuf,
+    metadata: EntryMetadata,
+    position: i64,
+}
+
+impl IndexEntry {
+    /// The path of this entry within the archive
+    pub fn archive_path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Fetches the size of the entry
+    pub fn size(&self) -> i64 {
+        self.metadata.st_size
+    }
+
+    /// Fetches metadata about the entry
+    pub fn metadata(&self) -> &EntryMetadata {
+        &self.metadata
+    }
+
+    /// The offset of this entry's header within the
+    /// uncompressed archive. Refer to
+    /// [`ArchiveEntry::header_position`]
+    pub fn header_position(&self) -> i64 {
+        self.position
+    }
+}
+
+impl ArchiveIndex {
+    /// Reads every header of the archive, starting over
+    /// if `reader` already read some of its entries
+    ///
+    /// # Errors
+    ///
+    /// Fails if any header cannot be read
+    pub fn build(mut reader: ArchiveReader) -> Result<Self> {
+        reader.rewind()?;
+
+        let mut entries = Vec::new();
+        let mut paths = BTreeMap::new();
+
+        while let Some(entry) = reader.next_entry()? {
+            paths.insert(normalize(entry.archive_path()), entries.len());
+            entries.push(IndexEntry {
+                path: entry.archive_path().to_path_buf(),
+                metadata: entry.metadata().clone(),
+                position: entry.header_position(),
+            });
+        }
+
+        // Entries of uncompressed tar and cpio archives follow each
+        // other, so reading can start at any header. Other formats
+        // keep state across entries, or data has to be decompressed
+        // from the start
+        let seek_format = reader.format().filter(|format| {
+            reader.input.is_seekable()
+                && !reader.is_filtered()
+                && matches!(format.family(), ArchiveFormat::Tar | ArchiveFormat::Cpio)
+        });
+
+        debug!(
+            "Indexed {} entries, reopened by {}",
+            entries.len(),
+            if seek_format.is_some() {
+                "seeking"
+            } else {
+                "rescanning"
+            }
+        );
+
+        Ok(Self {
+            reader,
+            entries,
+            paths,
+            seek_format,
+            cursor: None,
+        })
+    }
+
+    /// Looks up the entry stored at `path`
+    pub fn get<P: AsRef<Path>>(&self, path: P) -> Option<&IndexEntry> {
+        self.paths
+            .get(&normalize(path.as_ref()))
+            .map(|ordinal| &self.entries[*ordinal])
+    }
+
+    /// Whether the archive holds an entry at `path`
+    pub fn contains<P: AsRef<Path>>(&self, path: P) -> bool {
+        self.paths.contains_key(&normalize(path.as_ref()))
+    }
+
+    /// Lists the entries at or below `prefix`, sorted by
+    /// path. The prefix is matched by whole components,
+    /// so `src` matches `src/lib.rs` but not `src.txt`
+    pub fn list_prefix<P: AsRef<Path>>(&self, prefix: P) -> impl Iterator<Item = &IndexEntry> {
+        let prefix = normalize(prefix.as_ref());
+
+        // Paths are ordered by component, so everything below
+        // `prefix` directly follows it
+        self.paths
+            .range(prefix.clone()..)
+            .take_while(move |(path, _)| path.starts_with(&prefix))
+            .map(|(_, ordinal)| &self.entries[*ordinal])
+    }
+
+    /// Every entry of the archive, in archive order
+    pub fn entries(&self) -> &[IndexEntry] {
+        &self.entries
+    }
+
+    /// The number of entries in the archive
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Whether the archive has no entries
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Reopens the archive to read the entry stored at
+    /// `path`, returning `Ok(None)` if there is none.
+    ///
+    /// Uncompressed tar and cpio archives are reopened
+    /// right at the entry's header. Any other archive is
+    /// read again from the start, unless the entry comes
+    /// after the last one opened, in which case reading
+    /// simply carries on.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the archive cannot be reopened (e.g. a
+    /// [`crate::ArchiveSource::Stream`] cannot be read
+    /// twice), or with [`std::io::ErrorKind::InvalidData`]
+    /// if it no longer matches the index
+    pub fn open_entry<P: AsRef<Path>>(&mut self, path: P) -> Result<Option<ArchiveEntry<'_>>> {
+        let Some(&ordinal) = self.paths.get(&normalize(path.as_ref())) else {
+            return Ok(None);
+        };
+
+        let target = &self.entries[ordinal];
+        let cursor = self.cursor.take();
+
+        if let Some(format) = self.seek_format {
+            self.reader.seek_header(target.position as u64, format)?;
+        } else {
+            let skip = match cursor {
+                Some(cursor) if cursor <= ordinal => ordinal - cursor,
+                _ => {
+                    self.reader.rewind()?;
+                    ordinal
+                }
+            };
+
+            for _ in 0..skip {
+                if self.reader.next_entry()?.is_none() {
+                    return Err(changed(&target.path));
+                }
+            }
+        }
+
+        let entry = self.reader.next_entry()?;
+
+        match entry {
+            Some(entry) if normalize(entry.archive_path()) == normalize(&target.path) => {
+                self.cursor = Some(ordinal + 1);
+                Ok(Some(entry))
+            }
+            _ => Err(changed(&target.path)),
+        }
+    }
+
+    /// Gives the reader back
+    pub fn into_reader(self) -> ArchiveReader {
+        self.reader
+    }
+}
+
+/// The error returned when an entry is no longer where
+/// the index expects it
+fn changed(path: &Path) -> crate::error::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::InvalidData,
+        format!(
+            "archive changed since it was indexed, `{}` was not found",
+            path.display()
+        ),
+    )
+    .into()
+}
+
+/// Drops the components that do not change what a
+/// path refers to within an archive
+pub(crate) fn normalize(path: &Path) -> PathBuf {
+    path.components()
+        .filter(|component| !matches!(component, Component::CurDir))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::ArchiveFilter;
+    use crate::{ArchiveOptions, ArchiveWriter};
+    use std::io::Read;
+
+    /// The files of the fixture, in archive order. Some
+    /// span several blocks, so that entries do not start
+    /// at predictable offsets
+    fn files() -> Vec<(&'static str, Vec<u8>)> {
+        vec![
+            ("LICENSE", b"MIT License\n".repeat(100)),
+            ("README.md", b"# archive\n".to_vec()),
+            ("src/lib.rs", b"pub mod core;\n".repeat(300)),
+            ("src/main.rs", b"fn main() {}\n".to_vec()),
+        ]
+    }
+
+    /// Writes the fixture in `format`, compressed with `filter`
+    fn fixture(filter: ArchiveFilter, format: ArchiveFormat) -> Vec<u8> {
+        let options = ArchiveOptions::builder()
+            .filters([filter])
+            .formats([format])
+            .build();
+        let mut writer = ArchiveWriter::memory().handle_opts(options).open().unwrap();
+
+        writer.add_dir("src").unwrap();
+        for (path, data) in files() {
+            writer.add_bytes(path, &data).unwrap();
+        }
+
+        writer.finish_into_vec().unwrap()
+    }
+
+    fn check(index: &mut ArchiveIndex) {
+        assert!(index.contains("LICENSE"));
+        assert!(index.contains("./README.md"));
+        assert!(!index.contains("missing"));
+
+        assert_eq!(index.list_prefix("src").count(), 3);
+        assert!(index
+            .list_prefix("src")
+            .all(|entry| entry.archive_path().starts_with("src")));
+
+        // Out of archive order, so that reading has to go back
+        let files: BTreeMap<_, _> = files().into_iter().collect();
+        for path in ["src/main.rs", "README.md", "src/lib.rs", "LICENSE"] {
+            let size = index.get(path).unwrap().size();
+            let mut data = Vec::new();
+
+            index
+                .open_entry(path)
+                .unwrap()
+                .unwrap()
+                .read_to_end(&mut data)
+                .unwrap();
+
+            assert_eq!(data.len() as i64, size);
+            assert_eq!(data, files[path], "{path}");
+        }
+
+        assert!(index.open_entry("missing").unwrap().is_none());
+    }
+
+    #[test]
+    fn index_rescan() {
+        let reader = ArchiveReader::builder()
+            .source(fixture(
+                ArchiveFilter::Gzip,
+                ArchiveFormat::TarPaxRestricted,
+            ))
+            .open()
+            .unwrap();
+        let mut index = ArchiveIndex::build(reader).unwrap();
+
+        assert!(index.seek_format.is_none());
+        check(&mut index);
+    }
+
+    #[test]
+    fn index_seek() {
+        for format in [
+            ArchiveFormat::TarPaxRestricted,
+            ArchiveFormat::CpioSvr4Nocrc,
+        ] {
+            let reader = ArchiveReader::builder()
+                .source(fixture(ArchiveFilter::None, format))
+                .open()
+                .unwrap();
+            let mut index = ArchiveIndex::build(reader).unwrap();
+
+            assert_eq!(
+                index.seek_format.map(ArchiveFormat::family),
+                Some(format.family())
+            );
+            check(&mut index);
+        }
+    }
+}
@@ -1,16 +1,118 @@
 //! `archive`: Safe Rust bindings to `libarchive`
 
+#[cfg(feature = "tokio")]
+pub mod async_reader;
 pub mod core;
+pub mod diff;
+pub mod entry;
 pub mod error;
+pub mod extract;
+pub mod index;
 pub mod reader;
+#[cfg(feature = "serde")]
+mod rfc3339;
+pub mod source;
+mod target;
+pub mod verify;
 pub mod writer;
 
 pub use core::ArchiveOptions;
+pub use index::ArchiveIndex;
 pub use reader::ArchiveReader;
-// pub use writer::ArchiveWriter;
+pub use source::ArchiveSource;
+pub use writer::ArchiveWriter;
 
 use std::borrow::Cow;
 use std::ffi::CStr;
+use std::path::Path;
+
+use entry::EntryInfo;
+use error::Result;
+use extract::{ExtractOptions, ExtractReport};
+use writer::AddPathOptions;
+
+/// Extracts every entry of `archive` below `dest`,
+/// creating it if needed.
+///
+/// The format and compression are detected, and entries
+/// are extracted securely: paths that would end up outside
+/// of `dest` and writes through symlinks are refused.
+///
+/// ```no_run
+/// archive::extract("archive.tar.gz", "out/")?;
+/// # Ok::<(), archive::error::Error>(())
+/// ```
+///
+/// # Errors
+///
+/// Fails with the first error encountered, after every
+/// other entry has been extracted. Use
+/// [`ArchiveReader::extract_all`] for a report of every
+/// failed entry
+pub fn extract<S: Into<ArchiveSource>, P: AsRef<Path>>(
+    archive: S,
+    dest: P,
+) -> Result<ExtractReport> {
+    let mut reader = ArchiveReader::builder().source(archive).open()?;
+    let mut report = reader.extract_all(dest, &ExtractOptions::default())?;
+    reader.close()?;
+
+    if !report.is_ok() {
+        return Err(report.errors.swap_remove(0).error);
+    }
+
+    Ok(report)
+}
+
+/// Lists the entries of `archive`, detecting its format
+/// and compression
+///
+/// ```no_run
+/// for entry in archive::list("archive.zip")? {
+///     println!("{}", entry.path.display());
+/// }
+/// # Ok::<(), archive::error::Error>(())
+/// ```
+pub fn list<S: Into<ArchiveSource>>(archive: S) -> Result<Vec<EntryInfo>> {
+    let mut reader = ArchiveReader::builder().source(archive).open()?;
+    let entries = reader.list()?;
+    reader.close()?;
+
+    Ok(entries)
+}
+
+/// Creates (or truncates) the archive at `output`, holding
+/// `paths` and everything below them.
+///
+/// The format and compression are picked from the extension
+/// of `output`, e.g. `.tar.zst`, `.tgz` or `.zip`. Entries
+/// are named after `paths` with any leading `/`, `.` and
+/// `..` components dropped, and symlinks are stored rather
+/// than followed.
+///
+/// ```no_run
+/// archive::create("out.tar.zst", &["src", "Cargo.toml"])?;
+/// # Ok::<(), archive::error::Error>(())
+/// ```
+///
+/// # Errors
+///
+/// Fails if the extension is not known to `libarchive`,
+/// or if any path cannot be read
+pub fn create<O, I>(output: O, paths: I) -> Result<()>
+where
+    O: AsRef<Path>,
+    I: IntoIterator,
+    I::Item: AsRef<Path>,
+{
+    let mut writer = ArchiveWriter::builder().path(output.as_ref()).open()?;
+
+    for path in paths {
+        writer.add_path(path, &AddPathOptions::default())?;
+    }
+
+    writer.finish()
+}
 
 fn get_error<'a>(handle: *mut archive_sys::archive, result: i32) -> Cow<'a, str> {
     if result == 0 {
@@ -31,3 +133,40 @@ fn get_error<'a>(handle: *mut archive_sys::archive, result: i32) -> Cow<'a, str>
 
     err.to_string_lossy()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use entry::EntryKind;
+
+    #[test]
+    fn create_list_extract() {
+        let base = tempfile::tempdir().unwrap();
+
+        for name in ["out.tar.gz", "out.zip", "out.7z"] {
+            let output = base.path().join(name);
+            create(&output, ["src", "README.md"]).unwrap();
+
+            let entries = list(&output).unwrap();
+            let source = entries
+                .iter()
+                .find(|entry| entry.path == Path::new("src"))
+                .unwrap();
+            assert_eq!(source.kind, EntryKind::Directory);
+            assert!(entries
+                .iter()
+                .any(|entry| entry.path == Path::new("src/lib.rs")));
+
+            let dest = base.path().join(format!("{name}.d"));
+            let report = extract(&output, &dest).unwrap();
+            assert_eq!(report.entries, entries.len());
+
+            assert_eq!(
+                std::fs::read(dest.join("src/lib.rs")).unwrap(),
+                std::fs::read("src/lib.rs").unwrap()
+            );
+        }
+
+        assert!(create(base.path().join("out.unknown"), ["src"]).is_err());
+    }
+}
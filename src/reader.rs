@@ -1,7 +1,6 @@
 use std::cell::UnsafeCell;
-use std::ffi::{CStr, CString};
-use std::fs::{create_dir_all, OpenOptions};
-use std::io::Write;
+use std::ffi::CStr;
+use std::io::Read;
 use std::marker::PhantomData;
 use std::path::{Path, PathBuf};
 use std::sync::OnceLock;
@@ -9,206 +8,791 @@ use std::sync::OnceLock;
 use archive_sys::archive;
 use archive_sys::archive_entry;
 
-use bon::Builder;
-use log::{debug, error, warn};
+use bon::bon;
+use log::{error, warn};
 
 use crate::core::{ArchiveFilter, ArchiveFormat};
+use crate::diff::{CompareOptions, Diff};
+use crate::entry::EntryInfo;
 use crate::error::Result;
+use crate::extract::{DiskWriter, ExtractOptions, ExtractReport};
+use crate::source::{ArchiveSource, Client, Input};
+use crate::verify::VerifyReport;
 use crate::ArchiveOptions;
 
-#[derive(Builder)]
+/// A handle to an opened archive.
+///
+/// Readers are created through [`ArchiveReader::builder`],
+/// whose `open()` method returns an already opened reader,
+/// so a reader cannot be used before it was opened, nor
+/// opened twice.
+///
+/// Readers are [`Send`], so they can be opened in one thread
+/// and processed in another, but not [`Sync`]: a handle must
+/// never be used from two threads at once.
+///
+/// ```compile_fail
+/// fn assert_sync<T: Sync>() {}
+///
+/// assert_sync::<archive::ArchiveReader>();
+/// ```
+///
+/// ```no_run
+/// use archive::ArchiveReader;
+///
+/// let mut reader = ArchiveReader::builder().source("archive.tar.gz").open()?;
+///
+/// while let Some(entry) = reader.next_entry()? {
+///     println!("{}", entry.archive_path().display());
+/// }
+/// # Ok::<(), archive::error::Error>(())
+/// ```
 pub struct ArchiveReader {
-    #[builder(skip = std::ptr::null_mut())]
     handle: *mut archive,
 
-    #[builder(default = 1024)]
-    /// The size of the buffer to be used when
-    /// extracting files. Bigger buffers mean
-    /// less system calls (especially for large
-    /// files) at the cost of memory consumption.
-    ///
-    /// This value is used in the Rust library
-    /// when extracting files from the archive,
-    /// and is set to 1KiB by default.
-    chunk_size: usize,
+    pub(crate) chunk_size: usize,
+    pub(crate) handle_opts: ArchiveOptions,
+    pub(crate) input: Input,
+
+    /// The input a handle opened by
+    /// [`ArchiveReader::seek_header`] reads from, when it
+    /// could not share `input`
+    window: Option<Client>,
 
-    #[builder(default)]
-    /// Set of options to be passed for the
-    /// handle. Refer to [`crate::core::ArchiveOptions`]
-    /// for more information
-    handle_opts: ArchiveOptions,
+    /// Set once `libarchive` reports the end of the
+    /// archive or a fatal error, after which it
+    /// refuses to read headers
+    done: bool,
 
-    #[builder(into)]
-    path: PathBuf,
+    /// Set once a header was read, meaning that the
+    /// handle has to be reopened to get back to the
+    /// start of the archive
+    started: bool,
 
-    #[builder(skip)]
     _marker: PhantomData<UnsafeCell<archive>>,
 }
 
+// SAFETY: a `libarchive` handle has no affinity to the thread
+// that created it; all of its state (including the error
+// state) lives in the handle itself. Moving the reader moves
+// exclusive ownership of the handle, its input and any
+// callback data along with it. `Sync` is still ruled out by
+// the `UnsafeCell` marker, as handles are not thread safe.
+unsafe impl Send for ArchiveReader {}
+
+#[bon]
 impl ArchiveReader {
-    /// Opens `file_path` and marks the current instance as
-    /// having opened a file. This means that even after closing
-    pub fn open(&mut self) -> Result<()> {
-        if !self.handle.is_null() {
-            warn!("Attempting to re-open an already opened archive!");
-            return Err(crate::error::Error::AlreadyOpen);
-        }
+    /// Opens the archive read from `source`
+    #[builder(finish_fn = open)]
+    pub fn new(
+        /// Where to read the archive from. Paths are
+        /// converted into [`ArchiveSource::File`], and
+        /// byte vectors into [`ArchiveSource::Memory`]
+        #[builder(into)]
+        source: ArchiveSource,
+
+        /// The size of the buffer to be used when
+        /// extracting files. Bigger buffers mean
+        /// less system calls (especially for large
+        /// files) at the cost of memory consumption.
+        ///
+        /// This value is used in the Rust library
+        /// when extracting files from the archive,
+        /// and is set to 1KiB by default.
+        #[builder(default = 1024)]
+        chunk_size: usize,
+
+        /// Set of options to be passed for the
+        /// handle. Refer to [`crate::core::ArchiveOptions`]
+        /// for more information
+        #[builder(default)]
+        handle_opts: ArchiveOptions,
+    ) -> Result<Self> {
+        Self::from_input(source.into(), chunk_size, handle_opts)
+    }
+}
 
+impl ArchiveReader {
+    /// Opens a reader on an already converted input, which
+    /// lets several readers share the same source
+    pub(crate) fn from_input(
+        input: Input,
+        chunk_size: usize,
+        handle_opts: ArchiveOptions,
+    ) -> Result<Self> {
         let handle = unsafe { archive_sys::archive_read_new() };
 
         if handle.is_null() {
             return Err(crate::error::Error::Initialization);
         }
 
-        self.handle = handle;
-        self.set_options()?;
-        self.open_file()?;
+        // From here on, `Drop` frees the handle if
+        // anything goes wrong
+        let mut reader = Self {
+            handle,
+            chunk_size,
+            handle_opts,
+            input,
+            window: None,
+            done: false,
+            started: false,
+            _marker: PhantomData,
+        };
 
-        Ok(())
+        reader.open_handle()?;
+        Ok(reader)
     }
 
-    /// Returns an iterator containing the contents of
-    /// the archive if and only if the `open()` method
-    /// has been called.
-    pub fn entries<'a>(&'a self) -> Option<ArchiveIterator<'a>> {
-        if self.handle.is_null() {
-            None
-        } else {
-            Some(ArchiveIterator { archive: self })
+    /// Advances to the next entry of the archive.
+    ///
+    /// Returns `Ok(None)` on a clean end of archive. Headers
+    /// that `libarchive` could only partially read are still
+    /// returned, with the warning attached (see
+    /// [`ArchiveEntry::warning`]). Errors are returned as
+    /// `Err`, after which reading can continue unless the
+    /// error is fatal, in which case every subsequent call
+    /// returns `Ok(None)`.
+    ///
+    /// The entry mutably borrows the reader, since advancing
+    /// the stream invalidates it. Only one entry can be live
+    /// at a time, so reading data from a stale entry does
+    /// not compile:
+    ///
+    /// ```compile_fail
+    /// # use archive::ArchiveReader;
+    /// let mut reader = ArchiveReader::builder().source("archive.tar.gz").open()?;
+    ///
+    /// let mut first = reader.next_entry()?.unwrap();
+    /// let second = reader.next_entry()?.unwrap();
+    ///
+    /// first.extract(None::<&str>)?;
+    /// # Ok::<(), archive::error::Error>(())
+    /// ```
+    pub fn next_entry(&mut self) -> Result<Option<ArchiveEntry<'_>>> {
+        // A reader whose handle could not be reopened has
+        // nothing left to read
+        if self.done || self.handle.is_null() {
+            return Ok(None);
+        }
+
+        self.started = true;
+
+        match self.get_next_header() {
+            Ok(Some((entry, warning))) => Ok(Some(ArchiveEntry {
+                entry,
+                archive: self,
+                warning,
+                path: OnceLock::new(),
+                metadata: OnceLock::new(),
+
+                _marker: PhantomData,
+            })),
+            Ok(None) => {
+                self.done = true;
+                Ok(None)
+            }
+            Err(e) => {
+                self.done = e.is_fatal();
+                Err(e)
+            }
         }
     }
 
+    /// Lists every entry of the archive, rewinding the
+    /// reader first if entries were already read.
+    ///
+    /// Unlike [`ArchiveEntry`], the returned snapshots do
+    /// not borrow the reader.
+    pub fn list(&mut self) -> Result<Vec<EntryInfo>> {
+        self.rewind()?;
+
+        let mut entries = Vec::new();
+        while let Some(entry) = self.next_entry()? {
+            entries.push(entry.to_info());
+        }
+
+        Ok(entries)
+    }
+
+    /// Extracts every entry of the archive below `dest`,
+    /// rewinding the reader first if entries were
+    /// already read.
+    ///
+    /// Entry paths that would land outside of `dest`
+    /// (absolute paths, `..` components, or paths going
+    /// through a symlink) are rejected. Permissions and
+    /// modification times are restored.
+    ///
+    /// Entries that fail to extract do not stop the
+    /// extraction; their errors are collected in the
+    /// returned [`ExtractReport`] instead. Only errors
+    /// that leave the handle unusable end it early.
+    ///
+    /// Refer to [`ExtractOptions`] to extract on several
+    /// threads, or to report progress.
+    ///
+    /// ```no_run
+    /// use archive::extract::ExtractOptions;
+    /// use archive::ArchiveReader;
+    ///
+    /// let mut reader = ArchiveReader::builder().source("archive.zip").open()?;
+    /// let options = ExtractOptions::builder()
+    ///     .threads(4)
+    ///     .progress(|progress| println!("{}", progress.path.display()))
+    ///     .build();
+    ///
+    /// let report = reader.extract_all("out", &options)?;
+    ///
+    /// for failed in &report.errors {
+    ///     eprintln!("{:?}: {}", failed.path, failed.error);
+    /// }
+    /// # Ok::<(), archive::error::Error>(())
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// Fails when `dest` cannot be created, or when the
+    /// archive cannot be reopened
+    pub fn extract_all<P: AsRef<Path>>(
+        &mut self,
+        dest: P,
+        options: &ExtractOptions,
+    ) -> Result<ExtractReport> {
+        crate::extract::extract_all(self, dest.as_ref(), options)
+    }
+
+    /// Compares the entries of the archive with the tree
+    /// below `dir`, much like `bsdtar --diff`, rewinding
+    /// the reader first if entries were already read.
+    ///
+    /// Entries that are missing on disk are listed as
+    /// removed, and files on disk that are not in the
+    /// archive as added. Refer to [`CompareOptions`] for
+    /// what is compared.
+    ///
+    /// Entries that cannot be compared are listed in
+    /// [`Diff::errors`]. When the archive cannot be read
+    /// any further, no files are listed as added.
+    ///
+    /// ```no_run
+    /// use archive::diff::CompareOptions;
+    /// use archive::ArchiveReader;
+    ///
+    /// let mut reader = ArchiveReader::builder().source("release.tar.gz").open()?;
+    /// let options = CompareOptions::builder().contents(true).build();
+    /// let diff = reader.compare_dir("/srv/app", &options)?;
+    ///
+    /// for modified in &diff.modified {
+    ///     println!("{}: {:?}", modified.path.display(), modified.changes);
+    /// }
+    /// # Ok::<(), archive::error::Error>(())
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// Fails when `dir` cannot be read, or when the archive
+    /// cannot be reopened
+    pub fn compare_dir<P: AsRef<Path>>(
+        &mut self,
+        dir: P,
+        options: &CompareOptions,
+    ) -> Result<Diff> {
+        crate::diff::compare_dir(self, dir.as_ref(), options)
+    }
+
+    /// Compares the entries of this archive with those of
+    /// `other`, regardless of their format, compression
+    /// or the order of their entries. Both readers are
+    /// rewound first if entries were already read.
+    ///
+    /// Entries are matched by path, ignoring a leading
+    /// `./`. This archive is the old side: entries only
+    /// found in `other` are listed as added.
+    ///
+    /// ```no_run
+    /// use archive::diff::CompareOptions;
+    /// use archive::ArchiveReader;
+    ///
+    /// let mut old = ArchiveReader::builder().source("release-1.tar.gz").open()?;
+    /// let mut new = ArchiveReader::builder().source("release-2.zip").open()?;
+    ///
+    /// let options = CompareOptions::builder().contents(true).mtime(false).build();
+    /// let diff = old.diff(&mut new, &options)?;
+    ///
+    /// println!("added: {:?}, removed: {:?}", diff.added, diff.removed);
+    /// # Ok::<(), archive::error::Error>(())
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// Fails when either archive cannot be reopened
+    pub fn diff(&mut self, other: &mut ArchiveReader, options: &CompareOptions) -> Result<Diff> {
+        crate::diff::diff(self, other, options)
+    }
+
+    /// Reads the data of every entry to the end without
+    /// writing it anywhere, so that `libarchive` checks
+    /// it along the way (much like `unzip -t`), rewinding
+    /// the reader first if entries were already read.
+    ///
+    /// Problems with an entry are recorded in the returned
+    /// [`VerifyReport`] and do not stop verification; only
+    /// errors that leave the handle unusable end it early.
+    ///
+    /// Note that formats and filters only detect what they
+    /// record checksums for: zip and 7z entries carry a
+    /// CRC, whereas tar headers only checksum themselves.
+    ///
+    /// ```no_run
+    /// use archive::ArchiveReader;
+    ///
+    /// let mut reader = ArchiveReader::builder().source("archive.zip").open()?;
+    /// let report = reader.verify()?;
+    ///
+    /// for failed in report.failures() {
+    ///     eprintln!("{}: {:?}", failed.path.display(), failed.problem);
+    /// }
+    /// # Ok::<(), archive::error::Error>(())
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// Fails when the archive cannot be reopened
+    pub fn verify(&mut self) -> Result<VerifyReport> {
+        crate::verify::verify(self)
+    }
+
     /// Closes the file and frees the resources
     /// used by this struct.
     ///
     /// Normally, this function would be called
     /// on `Drop`, so this shouldn't be called
-    /// unless this `ArchiveReader` is going
-    /// to be re-used.
+    /// unless errors from closing the archive
+    /// need to be handled (`Drop` can only
+    /// log them).
+    pub fn close(mut self) -> Result<()> {
+        self.free()
+    }
+
+    /// Rewinds the reader to the start of the archive, so
+    /// that its entries can be read again (e.g. listing the
+    /// archive first, then extracting selected entries).
     ///
-    /// Does nothing when the archive isn't
-    /// `.open()`
-    pub fn close(&mut self) -> Result<()> {
-        if self.handle.is_null() {
+    /// `libarchive` streams cannot go backwards, so this
+    /// closes the handle and transparently reopens the
+    /// source: files are reopened, readers are seeked back
+    /// to their start and in-memory archives are read
+    /// again from their first byte.
+    ///
+    /// Rewinding a reader that has not read anything yet
+    /// does nothing, which is the only way
+    /// [`ArchiveSource::Stream`] readers can be rewound.
+    ///
+    /// # Errors
+    ///
+    /// Fails with [`std::io::ErrorKind::Unsupported`] for
+    /// streams that were already read from, leaving the
+    /// reader as it was. When the source cannot be
+    /// reopened, the reader reads no further entries.
+    pub fn rewind(&mut self) -> Result<()> {
+        if !self.started && !self.handle.is_null() {
             return Ok(());
         }
 
-        let ret = unsafe { archive_sys::archive_read_close(self.handle) };
+        if !self.input.is_seekable() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "streams cannot be rewound",
+            )
+            .into());
+        }
+
+        if let Err(e) = self.free() {
+            // Nothing else will be read from the old handle
+            warn!("Error whilst closing archive before rewinding: {}", e);
+        }
+
+        self.window = None;
+
+        if let Err(e) = self.reopen() {
+            self.done = true;
+            return Err(e);
+        }
+
+        self.done = false;
+        self.started = false;
+        Ok(())
+    }
+
+    /// Opens the input from its start on a new handle
+    fn reopen(&mut self) -> Result<()> {
+        self.input.rewind()?;
+
+        let handle = unsafe { archive_sys::archive_read_new() };
+
+        if handle.is_null() {
+            return Err(crate::error::Error::Initialization);
+        }
+
+        self.handle = handle;
+        self.open_handle()
+    }
+
+    /// Reopens the archive at the header found `offset`
+    /// bytes into the input (see
+    /// [`ArchiveEntry::header_position`]), so that the next
+    /// call to [`ArchiveReader::next_entry`] returns the
+    /// entry stored there.
+    ///
+    /// This only works for uncompressed archives whose
+    /// entries follow each other, such as tar and cpio,
+    /// which are read as `format`. Rewinding the reader
+    /// afterwards goes back to the start of the archive.
+    pub(crate) fn seek_header(&mut self, offset: u64, format: ArchiveFormat) -> Result<()> {
+        if !self.input.is_seekable() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "streams cannot be seeked",
+            )
+            .into());
+        }
+
+        if let Err(e) = self.free() {
+            warn!("Error whilst closing archive before seeking: {}", e);
+        }
+
+        // Nothing reads from the previous window anymore
+        self.window = None;
+        self.started = true;
+
+        if let Err(e) = self.open_at(offset, format) {
+            self.done = true;
+            return Err(e);
+        }
+
+        self.done = false;
+        Ok(())
+    }
+
+    /// Opens the input `offset` bytes in on a new handle,
+    /// reading it as `format`
+    fn open_at(&mut self, offset: u64, format: ArchiveFormat) -> Result<()> {
+        let handle = unsafe { archive_sys::archive_read_new() };
+
+        if handle.is_null() {
+            return Err(crate::error::Error::Initialization);
+        }
+
+        self.handle = handle;
+
+        let ret =
+            unsafe { archive_sys::archive_read_support_format_by_code(handle, format as i32) };
 
         if ret != archive_sys::ARCHIVE_OK as i32 {
-            return Err(crate::error::Error::Archive {
-                message: crate::get_error(self.handle, ret).to_string(),
-                code: ret,
-            });
+            return Err(crate::error::Error::from_handle(
+                handle,
+                ret,
+                "archive_read_support_format_by_code",
+            ));
         }
 
-        let ret = unsafe { archive_sys::archive_free(self.handle) };
+        let (ret, operation, window) = self.input.open_at(handle, offset)?;
+        self.window = window;
 
         if ret != archive_sys::ARCHIVE_OK as i32 {
-            return Err(crate::error::Error::Archive {
-                message: crate::get_error(self.handle, ret).to_string(),
-                code: ret,
-            });
+            return Err(crate::error::Error::from_handle(handle, ret, operation));
         }
+
         Ok(())
     }
 
-    fn open_file(&mut self) -> Result<()> {
-        let open_result = unsafe {
-            let filename = CString::new(self.path.as_mut_os_string().as_encoded_bytes())?.into_raw()
-                as *const i8;
+    /// The format of the archive, once a header was read
+    pub(crate) fn format(&self) -> Option<ArchiveFormat> {
+        if self.handle.is_null() {
+            return None;
+        }
 
-            archive_sys::archive_read_open_filename(self.handle, filename, self.chunk_size)
-        };
+        ArchiveFormat::from_code(unsafe { archive_sys::archive_format(self.handle) })
+    }
+
+    /// Whether the archive goes through any filter
+    /// besides the raw input
+    pub(crate) fn is_filtered(&self) -> bool {
+        if self.handle.is_null() {
+            return false;
+        }
+
+        let count = unsafe { archive_sys::archive_filter_count(self.handle) };
+
+        (0..count).any(|i| {
+            let code = unsafe { archive_sys::archive_filter_code(self.handle, i) };
+            code != archive_sys::ARCHIVE_FILTER_NONE as i32
+        })
+    }
+
+    /// Registers filters and formats on the fresh handle,
+    /// then opens the input on it
+    fn open_handle(&mut self) -> Result<()> {
+        self.set_options()?;
+
+        let (open_result, operation) = self
+            .input
+            .open(self.handle, self.handle_opts.handle_block_size)?;
 
         if open_result != archive_sys::ARCHIVE_OK as i32 {
-            return Err(crate::error::Error::Archive {
-                message: crate::get_error(self.handle, open_result).to_string(),
-                code: open_result,
-            });
+            let e = crate::error::Error::from_handle(self.handle, open_result, operation);
+
+            // A restricted handle fails to recognise input outside
+            // of its allowlist, so find out what the input really is
+            return Err(self.probe_rejected().unwrap_or(e));
+        }
+
+        if let Some(filter) = disallowed_filter(self.handle, &self.handle_opts) {
+            warn!("Rejecting archive compressed with {:?}", filter);
+            return Err(crate::error::Error::FilterNotAllowed(filter));
         }
 
         Ok(())
     }
 
-    fn set_options(&self) -> Result<()> {
-        let filter_result = if self.handle_opts.filter == ArchiveFilter::Auto {
-            unsafe { archive_sys::archive_read_support_filter_all(self.handle) }
+    /// Closes and frees the handle. The handle is
+    /// freed even when closing fails, and is nulled
+    /// so that it is only ever freed once.
+    fn free(&mut self) -> Result<()> {
+        let handle = std::mem::replace(&mut self.handle, std::ptr::null_mut());
+
+        if handle.is_null() {
+            return Ok(());
+        }
+
+        let ret = unsafe { archive_sys::archive_read_close(handle) };
+        let result = if ret != archive_sys::ARCHIVE_OK as i32 {
+            Err(crate::error::Error::from_handle(
+                handle,
+                ret,
+                "archive_read_close",
+            ))
         } else {
-            unsafe {
-                archive_sys::archive_read_support_filter_by_code(
-                    self.handle,
-                    self.handle_opts.filter as i32,
-                )
-            }
+            Ok(())
         };
 
-        if filter_result != archive_sys::ARCHIVE_OK as i32 {
-            return Err(crate::error::Error::Archive {
-                message: crate::get_error(self.handle, filter_result).to_string(),
-                code: filter_result,
-            });
+        let ret = unsafe { archive_sys::archive_read_free(handle) };
+
+        if ret != archive_sys::ARCHIVE_OK as i32 {
+            // The handle is gone at this point, so there
+            // is no error message to retrieve
+            error!("archive_read_free failed with code {}", ret);
         }
 
-        let format_result = if self.handle_opts.format == ArchiveFormat::Auto {
-            unsafe { archive_sys::archive_read_support_format_all(self.handle) }
-        } else {
-            unsafe {
-                archive_sys::archive_read_support_format_by_code(
+        result
+    }
+
+    fn set_options(&self) -> Result<()> {
+        if self.handle_opts.any_filter() {
+            let filter_result =
+                unsafe { archive_sys::archive_read_support_filter_all(self.handle) };
+
+            if filter_result != archive_sys::ARCHIVE_OK as i32 {
+                return Err(crate::error::Error::from_handle(
                     self.handle,
-                    self.handle_opts.format as i32,
-                )
+                    filter_result,
+                    "archive_read_support_filter_all",
+                ));
             }
-        };
+        } else {
+            for filter in &self.handle_opts.filters {
+                // External programs need a command line, which
+                // cannot be passed by code
+                if *filter == ArchiveFilter::Program {
+                    return Err(crate::error::Error::UnsupportedFilter(*filter));
+                }
 
-        if format_result != archive_sys::ARCHIVE_OK as i32 {
-            return Err(crate::error::Error::Archive {
-                message: crate::get_error(self.handle, format_result).to_string(),
-                code: format_result,
-            });
+                let filter_result = unsafe {
+                    archive_sys::archive_read_support_filter_by_code(self.handle, *filter as i32)
+                };
+
+                if filter_result == archive_sys::ARCHIVE_WARN {
+                    // e.g. falling back to an external `lrzip`
+                    warn!(
+                        "{:?}: {}",
+                        filter,
+                        crate::get_error(self.handle, filter_result)
+                    );
+                } else if filter_result != archive_sys::ARCHIVE_OK as i32 {
+                    error!(
+                        "{:?} is not supported: {}",
+                        filter,
+                        crate::get_error(self.handle, filter_result)
+                    );
+                    return Err(crate::error::Error::UnsupportedFilter(*filter));
+                }
+            }
+        }
+
+        if self.handle_opts.any_format() {
+            let format_result =
+                unsafe { archive_sys::archive_read_support_format_all(self.handle) };
+
+            if format_result != archive_sys::ARCHIVE_OK as i32 {
+                return Err(crate::error::Error::from_handle(
+                    self.handle,
+                    format_result,
+                    "archive_read_support_format_all",
+                ));
+            }
+        } else {
+            for format in &self.handle_opts.formats {
+                let format_result = unsafe {
+                    archive_sys::archive_read_support_format_by_code(self.handle, *format as i32)
+                };
+
+                if format_result != archive_sys::ARCHIVE_OK as i32 {
+                    return Err(crate::error::Error::from_handle(
+                        self.handle,
+                        format_result,
+                        "archive_read_support_format_by_code",
+                    ));
+                }
+            }
         }
 
         Ok(())
     }
 
-    fn get_next_header(&self) -> Option<*mut archive_entry> {
+    /// Opens the archive on a throwaway handle with every
+    /// filter and format enabled, returning the appropriate
+    /// error if the input turns out to be outside of the
+    /// allowlist.
+    fn probe_rejected(&mut self) -> Option<crate::error::Error> {
+        // The failed handle will not read anything else
+        // from the input, so it can be shared with the probe
+        self.input.rewind().ok()?;
+
+        let handle = unsafe { archive_sys::archive_read_new() };
+
+        if handle.is_null() {
+            return None;
+        }
+
+        let opened = unsafe {
+            archive_sys::archive_read_support_filter_all(handle);
+            archive_sys::archive_read_support_format_all(handle);
+
+            self.input
+                .open(handle, self.handle_opts.handle_block_size)
+                .map(|(open_result, _)| open_result)
+        };
+
+        let rejected = unsafe {
+            let mut entry: *mut archive_entry = std::ptr::null_mut();
+
+            if !matches!(opened, Ok(code) if code == archive_sys::ARCHIVE_OK as i32) {
+                None
+            } else if let Some(filter) = disallowed_filter(handle, &self.handle_opts) {
+                Some(crate::error::Error::FilterNotAllowed(filter))
+            } else if archive_sys::archive_read_next_header(handle, &mut entry)
+                < archive_sys::ARCHIVE_WARN
+            {
+                None
+            } else {
+                disallowed_format(handle, &self.handle_opts)
+                    .map(crate::error::Error::FormatNotAllowed)
+            }
+        };
+
+        unsafe { archive_sys::archive_read_free(handle) };
+        rejected
+    }
+
+    /// Reads the next header from the archive.
+    ///
+    /// Returns `Ok(None)` on a clean end of archive, and
+    /// the entry alongside `libarchive`'s warning text
+    /// when the header could only be partially read
+    /// (`ARCHIVE_WARN`).
+    fn get_next_header(&self) -> Result<Option<(*mut archive_entry, Option<String>)>> {
         let mut entry: *mut archive_entry = std::ptr::null_mut();
         let ret = unsafe { archive_sys::archive_read_next_header(self.handle, &mut entry) };
 
-        if ret == archive_sys::ARCHIVE_OK as i32 && !entry.is_null() {
-            Some(entry)
+        if ret == archive_sys::ARCHIVE_EOF as i32 {
+            return Ok(None);
+        }
+
+        if (ret != archive_sys::ARCHIVE_OK as i32 && ret != archive_sys::ARCHIVE_WARN)
+            || entry.is_null()
+        {
+            return Err(crate::error::Error::from_handle(
+                self.handle,
+                ret,
+                "archive_read_next_header",
+            ));
+        }
+
+        if let Some(format) = disallowed_format(self.handle, &self.handle_opts) {
+            error!(
+                "Archive format {:?} is not in the allowed set of formats",
+                format
+            );
+            return Err(crate::error::Error::FormatNotAllowed(format));
+        }
+
+        let warning = if ret == archive_sys::ARCHIVE_WARN {
+            let message = crate::get_error(self.handle, ret).to_string();
+            warn!("{}", message);
+
+            Some(message)
         } else {
             None
-        }
+        };
+
+        Ok(Some((entry, warning)))
     }
 }
 
-impl Drop for ArchiveReader {
-    fn drop(&mut self) {
-        if let Err(e) = self.close() {
-            panic!("failed to drop archive reader: {}", e);
-        }
+/// Returns the first filter used by the opened `handle`
+/// that is not allowed by `opts`
+fn disallowed_filter(handle: *mut archive, opts: &ArchiveOptions) -> Option<ArchiveFilter> {
+    if opts.any_filter() {
+        return None;
     }
-}
 
-pub struct ArchiveIterator<'a> {
-    archive: &'a ArchiveReader,
+    let count = unsafe { archive_sys::archive_filter_count(handle) };
+    let mut filters: Vec<ArchiveFilter> = (0..count)
+        .filter_map(|i| {
+            ArchiveFilter::from_code(unsafe { archive_sys::archive_filter_code(handle, i) })
+        })
+        .filter(|filter| *filter != ArchiveFilter::None)
+        .collect();
+
+    // The bottom of the filter chain is always the
+    // raw input, which only counts when it is alone
+    if filters.is_empty() {
+        filters.push(ArchiveFilter::None);
+    }
+
+    filters
+        .into_iter()
+        .find(|filter| !opts.allows_filter(*filter))
 }
 
-impl<'a> Iterator for ArchiveIterator<'a> {
-    type Item = ArchiveEntry<'a>;
+/// Returns the format detected by `handle` if it is not
+/// allowed by `opts`. Only meaningful after a header has
+/// been read
+fn disallowed_format(handle: *mut archive, opts: &ArchiveOptions) -> Option<ArchiveFormat> {
+    if opts.any_format() {
+        return None;
+    }
 
-    fn next(&mut self) -> Option<Self::Item> {
-        let entry = self.archive.get_next_header()?;
+    let code = unsafe { archive_sys::archive_format(handle) };
 
-        Some(ArchiveEntry {
-            entry,
-            archive: self.archive,
-            path: OnceLock::new(),
-            metadata: OnceLock::new(),
+    ArchiveFormat::from_code(code).filter(|format| !opts.allows_format(*format))
+}
 
-            _marker: PhantomData,
-        })
+impl Drop for ArchiveReader {
+    fn drop(&mut self) {
+        if let Err(e) = self.free() {
+            error!("Failed to close archive reader: {}", e);
+        }
     }
 }
 
@@ -217,17 +801,20 @@ impl<'a> Iterator for ArchiveIterator<'a> {
 //     pub tv_nsec: ::std::os::raw::c_long,
 // }
 
-#[derive(Default)]
+#[derive(Debug, Default, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct EntryMetadata {
     // st_dev: std::os::raw::c_ulong,
     // st_ino: std::os::raw::c_ulong,
     // st_nlink: std::os::raw::c_ulong,
-    st_mode: ::std::os::raw::c_uint,
+    #[cfg_attr(feature = "serde", serde(rename = "mode"))]
+    pub(crate) st_mode: ::std::os::raw::c_uint,
     // st_uid: ::std::os::raw::c_uint,
     // st_gid: ::std::os::raw::c_uint,
     // __pad0: ::std::os::raw::c_int,
     // st_rdev: std::os::raw::c_ulong,
-    st_size: ::std::os::raw::c_long,
+    #[cfg_attr(feature = "serde", serde(rename = "size"))]
+    pub(crate) st_size: ::std::os::raw::c_long,
     // st_atim: EntryTimeData,
     // st_mtim: EntryTimeData,
     // st_ctim: EntryTimeData,
@@ -243,9 +830,12 @@ impl EntryMetadata {
     }
 }
 
+/// An entry of an archive, borrowed from the
+/// [`ArchiveReader`] it was read from.
 pub struct ArchiveEntry<'a> {
-    archive: &'a ArchiveReader,
+    archive: &'a mut ArchiveReader,
     entry: *mut archive_entry,
+    warning: Option<String>,
 
     // Memoized fields:
     path: OnceLock<PathBuf>,
@@ -261,6 +851,14 @@ impl AsRef<Path> for ArchiveEntry<'_> {
 }
 
 impl ArchiveEntry<'_> {
+    /// The warning `libarchive` reported while reading
+    /// this entry's header, if any. Entries with a
+    /// warning are still usable, but some of their
+    /// metadata may be missing or inaccurate.
+    pub fn warning(&self) -> Option<&str> {
+        self.warning.as_deref()
+    }
+
     /// Fetches the path of this entry within the archive.
     ///
     /// This operation is only expensive on the first call.
@@ -287,6 +885,24 @@ impl ArchiveEntry<'_> {
         })
     }
 
+    /// Copies this entry's header into an owned
+    /// [`EntryInfo`], which outlives the entry
+    pub fn to_info(&self) -> EntryInfo {
+        unsafe { EntryInfo::from_raw(self.entry, self.archive_path().to_path_buf()) }
+    }
+
+    /// The offset of this entry's header within the
+    /// uncompressed archive, as reported by
+    /// `archive_read_header_position(3)`, or `-1` when
+    /// it is unknown
+    pub fn header_position(&self) -> i64 {
+        if self.archive.handle.is_null() {
+            return -1;
+        }
+
+        unsafe { archive_sys::archive_read_header_position(self.archive.handle) }
+    }
+
     /// Fetches the size of the entry
     pub fn size(&self) -> i64 {
         self.metadata().st_size
@@ -321,58 +937,69 @@ impl ArchiveEntry<'_> {
     /// Extracts the current entry onto an optional
     /// `base_dir`. When unset, `base_dir` defaults
     /// to the program's working directory
-    pub fn extract<P: AsRef<std::path::Path>>(
-        &self,
-        base_dir: Option<P>,
-    ) -> std::io::Result<usize> {
-        let mut total_read_bytes = 0;
-        let total_size = self.size();
-        let mut chunk = vec![0; self.archive.chunk_size];
-
-        let out_path = if let Some(base_dir) = base_dir {
-            base_dir.as_ref().join(self.archive_path())
-        } else {
-            std::env::current_dir()?.join(self.archive_path())
+    ///
+    /// The same checks as [`ArchiveReader::extract_all`]
+    /// apply: paths escaping `base_dir` are rejected,
+    /// and permissions and modification times are
+    /// restored.
+    ///
+    /// # Errors
+    ///
+    /// Fails with [`crate::error::Error::UnsafePath`]
+    /// when the entry would be written outside of
+    /// `base_dir`, with [`crate::error::Error::Io`] when
+    /// the output cannot be written, and with
+    /// [`crate::error::Error::Archive`] (carrying this
+    /// entry's path) when its data cannot be read
+    pub fn extract<P: AsRef<std::path::Path>>(&mut self, base_dir: Option<P>) -> Result<usize> {
+        let base_dir = match base_dir {
+            Some(base_dir) => base_dir.as_ref().to_path_buf(),
+            None => std::env::current_dir()?,
         };
 
-        if let Some(parent) = out_path.parent() {
-            create_dir_all(parent)?;
-        }
-
-        debug!("Extracting file `{}`", self.archive_path().display());
+        let mut disk = DiskWriter::new(&base_dir, self.archive.chunk_size)?;
+        let written = disk.write_entry(self)?;
+        disk.close()?;
 
-        let mut out_file = OpenOptions::new()
-            .create(true)
-            .truncate(true)
-            .write(true)
-            .open(out_path)?;
-
-        while total_read_bytes != total_size {
-            let bytes_read = unsafe {
-                archive_sys::archive_read_data(
-                    self.archive.handle,
-                    chunk.as_mut_ptr() as *mut std::ffi::c_void,
-                    self.archive.chunk_size,
-                )
-            };
+        Ok(written as usize)
+    }
 
-            total_read_bytes += bytes_read as i64;
+    /// The underlying `libarchive` entry
+    pub(crate) fn raw(&self) -> *mut archive_entry {
+        self.entry
+    }
 
-            if bytes_read > 0 {
-                assert!(!chunk.is_empty(), "chunk is empty when reported otherwise");
+    /// Reads this entry's data into `buf`, returning
+    /// `Ok(0)` once all of it was read
+    pub(crate) fn read_data(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let bytes_read = unsafe {
+            archive_sys::archive_read_data(
+                self.archive.handle,
+                buf.as_mut_ptr() as *mut std::ffi::c_void,
+                buf.len(),
+            )
+        };
 
-                let read_slice = &chunk[..bytes_read as usize];
-                out_file.write_all(read_slice)?;
-            }
+        if bytes_read < 0 {
+            return Err(crate::error::Error::from_handle(
+                self.archive.handle,
+                bytes_read as i32,
+                "archive_read_data",
+            )
+            .with_path(self.archive_path()));
         }
 
-        assert_eq!(
-            total_read_bytes, total_size,
-            "mismatch between file size in archive and total read data"
-        );
+        Ok(bytes_read as usize)
+    }
+}
 
-        debug!("File `{}` extracted", self.archive_path().display());
-        Ok(total_read_bytes as usize)
+impl Read for ArchiveEntry<'_> {
+    /// Reads this entry's data. Errors reported by
+    /// `libarchive` are converted from
+    /// [`crate::error::Error::Archive`], and carry
+    /// this entry's path.
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        Ok(self.read_data(buf)?)
     }
 }
 
@@ -383,22 +1010,30 @@ mod tests {
     #[test]
     fn test_reader() {
         let options = ArchiveOptions::builder()
-            .filter(ArchiveFilter::Gzip)
-            .format(ArchiveFormat::Tar)
+            .filters([ArchiveFilter::Gzip])
+            .formats([ArchiveFormat::Tar])
             .build();
 
-        let mut reader = ArchiveReader::builder()
-            .path("archive.tar.gz")
+        let result = ArchiveReader::builder()
+            .source("archive.tar.gz")
             .handle_opts(options)
-            .build();
-
-        let result = reader.open();
-        dbg!(&result);
+            .open();
+        dbg!(result.as_ref().err());
 
         assert!(result.is_ok());
-        assert!(reader.entries().unwrap().count() >= 1);
+        let mut reader = result.unwrap();
+
+        let mut count = 0;
+        while reader.next_entry().unwrap().is_some() {
+            count += 1;
+        }
 
-        for file in reader.entries().unwrap() {
+        assert!(count >= 1);
+        reader.rewind().unwrap();
+
+        let mut listed = 0;
+        while let Some(file) = reader.next_entry().unwrap() {
+            listed += 1;
             println!(
                 "Found\t: {} ({} bytes)",
                 file.archive_path().display(),
@@ -407,5 +1042,308 @@ mod tests {
 
             file.archive_path();
         }
+
+        assert_eq!(count, listed);
+    }
+
+    #[test]
+    fn test_reader_rewind_sources() {
+        let data = std::fs::read("archive.tar.gz").unwrap();
+        let sources = [
+            ArchiveSource::from(data.clone()),
+            ArchiveSource::reader(std::io::Cursor::new(data)),
+        ];
+
+        for source in sources {
+            let mut reader = ArchiveReader::builder().source(source).open().unwrap();
+            let mut passes = Vec::new();
+
+            for _ in 0..2 {
+                let mut paths = Vec::new();
+                while let Some(file) = reader.next_entry().unwrap() {
+                    paths.push(file.archive_path().to_path_buf());
+                }
+
+                passes.push(paths);
+                reader.rewind().unwrap();
+            }
+
+            assert!(!passes[0].is_empty());
+            assert_eq!(passes[0], passes[1]);
+        }
+    }
+
+    #[test]
+    fn test_reader_interrupted() {
+        /// Gets interrupted before every read
+        struct Interrupted<R> {
+            inner: R,
+            interrupt: bool,
+        }
+
+        impl<R: Read> Read for Interrupted<R> {
+            fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+                self.interrupt = !self.interrupt;
+
+                if self.interrupt {
+                    return Err(std::io::ErrorKind::Interrupted.into());
+                }
+
+                self.inner.read(buf)
+            }
+        }
+
+        let data = std::fs::read("archive.tar.gz").unwrap();
+        let source = ArchiveSource::stream(Interrupted {
+            inner: std::io::Cursor::new(data),
+            interrupt: false,
+        });
+        let mut reader = ArchiveReader::builder().source(source).open().unwrap();
+
+        let mut count = 0;
+        while let Some(mut entry) = reader.next_entry().unwrap() {
+            std::io::copy(&mut entry, &mut std::io::sink()).unwrap();
+            count += 1;
+        }
+
+        assert!(count > 0);
+    }
+
+    #[test]
+    fn test_reader_failed_rewind() {
+        let data = std::fs::read("archive.tar.gz").unwrap();
+        let mut reader = ArchiveReader::builder()
+            .source(ArchiveSource::stream(std::io::Cursor::new(data)))
+            .open()
+            .unwrap();
+
+        let first = reader
+            .next_entry()
+            .unwrap()
+            .unwrap()
+            .archive_path()
+            .to_owned();
+
+        // Streams are refused before anything is closed, so
+        // reading carries on where it left off
+        let error = reader.rewind().unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::Unsupported);
+
+        let second = reader
+            .next_entry()
+            .unwrap()
+            .unwrap()
+            .archive_path()
+            .to_owned();
+        assert_ne!(first, second);
+
+        // Files that are gone cannot be reopened, which
+        // leaves nothing to read
+        let path = std::env::temp_dir().join("archive-failed-rewind.tar.gz");
+        std::fs::copy("archive.tar.gz", &path).unwrap();
+
+        let mut reader = ArchiveReader::builder().source(&path).open().unwrap();
+        reader.next_entry().unwrap().unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(reader.rewind().is_err());
+        assert!(reader.next_entry().unwrap().is_none());
+        assert!(reader.format().is_none());
+        assert!(!reader.is_filtered());
+    }
+
+    #[test]
+    fn test_reader_unsupported_filter() {
+        let options = ArchiveOptions::builder()
+            .filters([ArchiveFilter::Gzip, ArchiveFilter::Program])
+            .build();
+
+        let result = ArchiveReader::builder()
+            .source("archive.tar.gz")
+            .handle_opts(options)
+            .open();
+
+        assert!(matches!(
+            result,
+            Err(crate::error::Error::UnsupportedFilter(
+                ArchiveFilter::Program
+            ))
+        ));
+    }
+
+    #[test]
+    fn test_reader_rejects_format() {
+        let options = ArchiveOptions::builder()
+            .formats([ArchiveFormat::Zip, ArchiveFormat::P7zip])
+            .build();
+
+        let result = ArchiveReader::builder()
+            .source("archive.tar.gz")
+            .handle_opts(options)
+            .open();
+        dbg!(result.as_ref().err());
+
+        assert!(matches!(
+            result,
+            Err(crate::error::Error::FormatNotAllowed(format)) if format.family() == ArchiveFormat::Tar
+        ));
+    }
+
+    #[test]
+    fn test_reader_rejects_filter() {
+        let options = ArchiveOptions::builder()
+            .filters([ArchiveFilter::Zstd, ArchiveFilter::Xz])
+            .build();
+
+        let result = ArchiveReader::builder()
+            .source("archive.tar.gz")
+            .handle_opts(options)
+            .open();
+        dbg!(result.as_ref().err());
+
+        assert!(matches!(
+            result,
+            Err(crate::error::Error::FilterNotAllowed(ArchiveFilter::Gzip))
+        ));
+    }
+
+    #[test]
+    fn test_reader_read() {
+        let mut reader = ArchiveReader::builder()
+            .source("archive.tar.gz")
+            .open()
+            .unwrap();
+
+        while let Some(mut file) = reader.next_entry().unwrap() {
+            let mut data = Vec::new();
+            file.read_to_end(&mut data).unwrap();
+
+            assert_eq!(data.len() as i64, file.size());
+        }
+    }
+
+    #[test]
+    fn test_reader_list() {
+        use crate::entry::{EntryHeader, EntryKind};
+        use crate::ArchiveWriter;
+        use std::time::{Duration, SystemTime};
+
+        let data = b"MIT License\n";
+        let mtime = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let header = EntryHeader::builder()
+            .path("LICENSE")
+            .mode(0o644)
+            .size(data.len() as u64)
+            .mtime(mtime)
+            .build();
+
+        let options = ArchiveOptions::builder()
+            .filters([ArchiveFilter::Gzip])
+            .formats([ArchiveFormat::TarPaxRestricted])
+            .build();
+        let mut writer = ArchiveWriter::memory().handle_opts(options).open().unwrap();
+        writer.add_dir("src").unwrap();
+        writer.add_entry(&header, &data[..]).unwrap();
+
+        let mut reader = ArchiveReader::builder()
+            .source(writer.finish_into_vec().unwrap())
+            .open()
+            .unwrap();
+
+        // Entries are read before listing, which starts over
+        reader.next_entry().unwrap();
+        let entries = reader.list().unwrap();
+
+        let license = entries
+            .iter()
+            .find(|entry| entry.path == Path::new("LICENSE"))
+            .unwrap();
+
+        assert_eq!(license.kind, EntryKind::File);
+        assert_eq!(license.size, Some(data.len() as u64));
+        assert_eq!(license.mtime, Some(mtime));
+        assert!(!license.encrypted);
+
+        assert!(entries
+            .iter()
+            .any(|entry| entry.kind == EntryKind::Directory));
+        assert_eq!(entries, entries.clone());
+
+        let paths = |entries: Vec<EntryInfo>| {
+            entries
+                .into_iter()
+                .map(|entry| entry.path)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(paths(entries), paths(reader.list().unwrap()));
+    }
+
+    #[test]
+    fn test_reader_send() {
+        let mut reader = ArchiveReader::builder()
+            .source("archive.tar.gz")
+            .open()
+            .unwrap();
+
+        let count = std::thread::spawn(move || {
+            let mut count = 0;
+            while reader.next_entry().unwrap().is_some() {
+                count += 1;
+            }
+
+            count
+        })
+        .join()
+        .unwrap();
+
+        assert!(count >= 1);
+    }
+
+    #[test]
+    fn test_reader_close() {
+        let reader = ArchiveReader::builder()
+            .source("archive.tar.gz")
+            .open()
+            .unwrap();
+
+        // Closing consumes the reader, so `Drop` runs right
+        // after and must not free the handle a second time
+        assert!(reader.close().is_ok());
+    }
+
+    #[test]
+    fn test_reader_missing() {
+        let result = ArchiveReader::builder()
+            .source("does-not-exist.tar.gz")
+            .open();
+        dbg!(result.as_ref().err());
+
+        let err = result.err().unwrap();
+        assert_eq!(err.status(), Some(crate::error::Status::Fatal));
+        assert_eq!(err.kind(), std::io::ErrorKind::NotFound);
+        assert_eq!(
+            std::io::Error::from(err).kind(),
+            std::io::ErrorKind::NotFound
+        );
+    }
+
+    #[test]
+    fn test_reader_truncated() {
+        let data = std::fs::read("archive.tar.gz").unwrap();
+        let path = std::env::temp_dir().join("archive-truncated.tar.gz");
+        std::fs::write(&path, &data[..data.len() / 2]).unwrap();
+
+        // Depending on where the cut lands, either opening or
+        // reading a header fails, but never silently
+        let result = ArchiveReader::builder()
+            .source(&path)
+            .open()
+            .and_then(|mut reader| {
+                while reader.next_entry()?.is_some() {}
+                Ok(())
+            });
+        dbg!(&result);
+
+        assert!(result.is_err());
     }
 }
@@ -0,0 +1,86 @@
+//! (De)serializes optional timestamps as RFC 3339 strings,
+//! for use with `#[serde(with = "crate::rfc3339")]`
+
+use std::time::SystemTime;
+
+use serde::de::Error as _;
+use serde::ser::Error as _;
+use serde::{Deserialize, Deserializer, Serializer};
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+
+pub(crate) fn serialize<S: Serializer>(
+    time: &Option<SystemTime>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    match time {
+        Some(time) => {
+            let formatted = OffsetDateTime::from(*time)
+                .format(&Rfc3339)
+                .map_err(S::Error::custom)?;
+
+            serializer.serialize_some(&formatted)
+        }
+        None => serializer.serialize_none(),
+    }
+}
+
+pub(crate) fn deserialize<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<SystemTime>, D::Error> {
+    Option::<String>::deserialize(deserializer)?
+        .map(|time| {
+            OffsetDateTime::parse(&time, &Rfc3339)
+                .map(SystemTime::from)
+                .map_err(D::Error::custom)
+        })
+        .transpose()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Stamp(#[serde(with = "super")] Option<SystemTime>);
+
+    fn round_trip(time: Option<SystemTime>, expected: serde_json::Value) {
+        let json = serde_json::to_value(Stamp(time)).unwrap();
+        assert_eq!(json, expected);
+
+        let parsed: Stamp = serde_json::from_value(json).unwrap();
+        assert_eq!(parsed, Stamp(time));
+    }
+
+    #[test]
+    fn timestamps() {
+        round_trip(None, serde_json::Value::Null);
+        round_trip(Some(UNIX_EPOCH), "1970-01-01T00:00:00Z".into());
+
+        // Sub-second precision is kept, down to nanoseconds
+        round_trip(
+            Some(UNIX_EPOCH + Duration::new(1_700_000_000, 123_456_789)),
+            "2023-11-14T22:13:20.123456789Z".into(),
+        );
+
+        // Before the epoch
+        round_trip(
+            Some(UNIX_EPOCH - Duration::from_millis(1_500)),
+            "1969-12-31T23:59:58.5Z".into(),
+        );
+    }
+
+    #[test]
+    fn offsets() {
+        // Other offsets are accepted, and come out in UTC
+        let parsed: Stamp = serde_json::from_str(r#""2023-11-15T00:13:20+02:00""#).unwrap();
+        assert_eq!(
+            parsed,
+            Stamp(Some(UNIX_EPOCH + Duration::from_secs(1_700_000_000)))
+        );
+
+        assert!(serde_json::from_str::<Stamp>(r#""yesterday""#).is_err());
+    }
+}
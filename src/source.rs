@@ -0,0 +1,396 @@
+use std::ffi::{c_void, CString};
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use archive_sys::archive;
+use log::error;
+
+/// Anything that can be read from and seeked,
+/// such as a [`std::fs::File`] or a
+/// [`std::io::Cursor`]. Sources must be [`Send`]
+/// so that readers can be moved across threads.
+pub trait ReadSeek: Read + Seek + Send {}
+
+impl<T: Read + Seek + Send> ReadSeek for T {}
+
+/// Where an [`crate::reader::ArchiveReader`] reads
+/// the archive from.
+///
+/// Every source but [`ArchiveSource::Stream`] can be
+/// read from the beginning again, which is what allows
+/// readers to be rewound (see
+/// [`crate::reader::ArchiveReader::rewind`])
+pub enum ArchiveSource {
+    /// A file on disk
+    File(PathBuf),
+    /// An archive held in memory
+    Memory(Vec<u8>),
+    /// Any seekable reader. The seek callback is
+    /// also handed to `libarchive`, which lets
+    /// formats such as zip read their central
+    /// directory instead of streaming
+    Reader(Box<dyn ReadSeek>),
+    /// A reader that can only be read once, such as a
+    /// socket or a pipe. Formats that need to seek
+    /// (e.g. 7z) cannot be read from streams, and
+    /// readers of streams cannot be rewound
+    Stream(Box<dyn Read + Send>),
+}
+
+impl ArchiveSource {
+    /// Reads the archive from `reader`
+    pub fn reader(reader: impl Read + Seek + Send + 'static) -> Self {
+        Self::Reader(Box::new(reader))
+    }
+
+    /// Reads the archive from `reader`, without seeking
+    pub fn stream(reader: impl Read + Send + 'static) -> Self {
+        Self::Stream(Box::new(reader))
+    }
+}
+
+impl From<PathBuf> for ArchiveSource {
+    fn from(value: PathBuf) -> Self {
+        Self::File(value)
+    }
+}
+
+impl From<&Path> for ArchiveSource {
+    fn from(value: &Path) -> Self {
+        Self::File(value.to_path_buf())
+    }
+}
+
+impl From<&PathBuf> for ArchiveSource {
+    fn from(value: &PathBuf) -> Self {
+        Self::File(value.clone())
+    }
+}
+
+impl From<&str> for ArchiveSource {
+    fn from(value: &str) -> Self {
+        Self::File(PathBuf::from(value))
+    }
+}
+
+impl From<String> for ArchiveSource {
+    fn from(value: String) -> Self {
+        Self::File(PathBuf::from(value))
+    }
+}
+
+impl From<Vec<u8>> for ArchiveSource {
+    fn from(value: Vec<u8>) -> Self {
+        Self::Memory(value)
+    }
+}
+
+/// An [`ArchiveSource`] in the shape a read
+/// handle needs it.
+pub(crate) enum Input {
+    File(PathBuf),
+    Memory(Arc<Vec<u8>>),
+    Reader(Client),
+}
+
+impl From<ArchiveSource> for Input {
+    fn from(value: ArchiveSource) -> Self {
+        match value {
+            ArchiveSource::File(path) => Self::File(path),
+            ArchiveSource::Memory(data) => Self::Memory(Arc::new(data)),
+            ArchiveSource::Reader(reader) => Self::Reader(Client::new(Stream::Seekable(reader))),
+            ArchiveSource::Stream(reader) => Self::Reader(Client::new(Stream::Forward(reader))),
+        }
+    }
+}
+
+impl Input {
+    /// Creates another input reading the same archive,
+    /// so that it can be opened on an independent
+    /// handle. Readers cannot be duplicated.
+    pub(crate) fn try_clone(&self) -> Option<Self> {
+        match self {
+            Self::File(path) => Some(Self::File(path.clone())),
+            Self::Memory(data) => Some(Self::Memory(Arc::clone(data))),
+            Self::Reader(_) => None,
+        }
+    }
+
+    /// Hands the input to `handle` and opens it,
+    /// returning the `libarchive` result code and
+    /// the name of the function that produced it
+    pub(crate) fn open(
+        &mut self,
+        handle: *mut archive,
+        block_size: usize,
+    ) -> crate::error::Result<(i32, &'static str)> {
+        match self {
+            Self::File(path) => {
+                let filename = CString::new(path.as_os_str().as_encoded_bytes())?;
+                let ret = unsafe {
+                    archive_sys::archive_read_open_filename(handle, filename.as_ptr(), block_size)
+                };
+
+                Ok((ret, "archive_read_open_filename"))
+            }
+            Self::Memory(data) => {
+                let ret = unsafe {
+                    archive_sys::archive_read_open_memory(handle, data.as_ptr() as _, data.len())
+                };
+
+                Ok((ret, "archive_read_open_memory"))
+            }
+            Self::Reader(client) => {
+                let ret = unsafe {
+                    archive_sys::archive_read_set_read_callback(handle, Some(read_callback));
+
+                    // Without a seek callback, `libarchive` falls
+                    // back to streaming (or rejects the format)
+                    if let Stream::Seekable(_) = client.state().reader {
+                        archive_sys::archive_read_set_seek_callback(handle, Some(seek_callback));
+                    }
+
+                    archive_sys::archive_read_set_callback_data(
+                        handle,
+                        client.state_mut() as *mut ClientState as *mut c_void,
+                    );
+
+                    archive_sys::archive_read_open1(handle)
+                };
+
+                Ok((ret, "archive_read_open1"))
+            }
+        }
+    }
+
+    /// Whether the input can be read from an arbitrary
+    /// offset (see [`Input::open_at`])
+    pub(crate) fn is_seekable(&self) -> bool {
+        match self {
+            Self::File(_) | Self::Memory(_) => true,
+            Self::Reader(client) => matches!(client.state().reader, Stream::Seekable(_)),
+        }
+    }
+
+    /// Opens the input on `handle`, starting `offset` bytes
+    /// into it. `libarchive` sees a stream beginning at
+    /// `offset`, without any way to seek back.
+    ///
+    /// Files are opened anew for this, and the returned
+    /// client must be kept alive for as long as `handle`
+    /// is open. Must only be called while no other handle
+    /// is reading from this input.
+    pub(crate) fn open_at(
+        &mut self,
+        handle: *mut archive,
+        offset: u64,
+    ) -> crate::error::Result<(i32, &'static str, Option<Client>)> {
+        match self {
+            Self::File(path) => {
+                let mut file = std::fs::File::open(path)?;
+                file.seek(SeekFrom::Start(offset))?;
+
+                let mut client = Client::new(Stream::Forward(Box::new(file)));
+                let ret = unsafe { client.open_stream(handle) };
+
+                Ok((ret, "archive_read_open1", Some(client)))
+            }
+            Self::Memory(data) => {
+                let data = data.get(offset as usize..).unwrap_or_default();
+                let ret = unsafe {
+                    archive_sys::archive_read_open_memory(handle, data.as_ptr() as _, data.len())
+                };
+
+                Ok((ret, "archive_read_open_memory", None))
+            }
+            Self::Reader(client) => {
+                match &mut client.state_mut().reader {
+                    Stream::Seekable(reader) => reader.seek(SeekFrom::Start(offset))?,
+                    Stream::Forward(_) => {
+                        return Err(std::io::Error::new(
+                            std::io::ErrorKind::Unsupported,
+                            "streams cannot be seeked",
+                        )
+                        .into())
+                    }
+                };
+
+                let ret = unsafe { client.open_stream(handle) };
+
+                Ok((ret, "archive_read_open1", None))
+            }
+        }
+    }
+
+    /// Moves the input back to the start of the
+    /// archive. Must only be called while no handle
+    /// is reading from this input.
+    pub(crate) fn rewind(&mut self) -> crate::error::Result<()> {
+        if let Self::Reader(client) = self {
+            match &mut client.state_mut().reader {
+                Stream::Seekable(reader) => reader.rewind()?,
+                Stream::Forward(_) => {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::Unsupported,
+                        "streams cannot be rewound",
+                    )
+                    .into())
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// The client data handed to `libarchive` for
+/// [`ArchiveSource::Reader`] and [`ArchiveSource::Stream`].
+///
+/// It is kept behind a raw pointer, as `libarchive`
+/// holds on to it for as long as the handle is open.
+pub(crate) struct Client(*mut ClientState);
+
+struct ClientState {
+    reader: Stream,
+    buffer: Vec<u8>,
+}
+
+/// A reader behind a [`Client`], which is only
+/// seeked when it can be
+enum Stream {
+    Seekable(Box<dyn ReadSeek>),
+    Forward(Box<dyn Read + Send>),
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            Self::Seekable(reader) => reader.read(buf),
+            Self::Forward(reader) => reader.read(buf),
+        }
+    }
+}
+
+impl Client {
+    fn new(reader: Stream) -> Self {
+        let state = Box::new(ClientState {
+            reader,
+            buffer: vec![0; 10240],
+        });
+
+        Self(Box::into_raw(state))
+    }
+
+    /// The state shared with `libarchive`. Only to be
+    /// used while no callback is running, which holds
+    /// outside of calls into a handle using it
+    fn state(&self) -> &ClientState {
+        unsafe { &*self.0 }
+    }
+
+    /// Mutable access to the state, also used to hand
+    /// it to `libarchive`, so that anything a handle
+    /// may do through it requires a unique borrow
+    fn state_mut(&mut self) -> &mut ClientState {
+        unsafe { &mut *self.0 }
+    }
+
+    /// Opens `handle` on this client with a read callback
+    /// only, so that `libarchive` never seeks it
+    unsafe fn open_stream(&mut self, handle: *mut archive) -> i32 {
+        let state = self.state_mut() as *mut ClientState;
+
+        unsafe {
+            archive_sys::archive_read_set_read_callback(handle, Some(read_callback));
+            archive_sys::archive_read_set_callback_data(handle, state as *mut c_void);
+
+            archive_sys::archive_read_open1(handle)
+        }
+    }
+}
+
+// SAFETY: the state is only reachable through this pointer,
+// which is uniquely owned, and the reader inside it is `Send`
+unsafe impl Send for Client {}
+
+impl Drop for Client {
+    fn drop(&mut self) {
+        // Handles are always freed before their input,
+        // so nothing can be using the state anymore
+        drop(unsafe { Box::from_raw(self.0) });
+    }
+}
+
+/// Reports an IO error raised by a callback to `libarchive`
+pub(crate) unsafe fn set_io_error(handle: *mut archive, err: &std::io::Error) {
+    let message = CString::new(err.to_string()).unwrap_or_default();
+
+    unsafe {
+        archive_sys::archive_set_error(
+            handle,
+            err.raw_os_error().unwrap_or(-1),
+            c"%s".as_ptr(),
+            message.as_ptr(),
+        );
+    }
+}
+
+unsafe extern "C" fn read_callback(
+    handle: *mut archive,
+    client_data: *mut c_void,
+    buffer: *mut *const c_void,
+) -> archive_sys::la_ssize_t {
+    let state = unsafe { &mut *(client_data as *mut ClientState) };
+
+    let read = loop {
+        match state.reader.read(&mut state.buffer) {
+            // Signals interrupting the read are no reason to fail
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            read => break read,
+        }
+    };
+
+    match read {
+        Ok(read) => {
+            unsafe { *buffer = state.buffer.as_ptr() as *const c_void };
+            read as archive_sys::la_ssize_t
+        }
+        Err(e) => {
+            error!("Failed to read from archive source: {}", e);
+            unsafe { set_io_error(handle, &e) };
+
+            archive_sys::ARCHIVE_FATAL as archive_sys::la_ssize_t
+        }
+    }
+}
+
+unsafe extern "C" fn seek_callback(
+    handle: *mut archive,
+    client_data: *mut c_void,
+    offset: archive_sys::la_int64_t,
+    whence: std::ffi::c_int,
+) -> archive_sys::la_int64_t {
+    let state = unsafe { &mut *(client_data as *mut ClientState) };
+
+    let Stream::Seekable(reader) = &mut state.reader else {
+        return archive_sys::ARCHIVE_FATAL as archive_sys::la_int64_t;
+    };
+
+    let position = match whence {
+        libc::SEEK_SET => SeekFrom::Start(offset as u64),
+        libc::SEEK_CUR => SeekFrom::Current(offset),
+        libc::SEEK_END => SeekFrom::End(offset),
+        _ => return archive_sys::ARCHIVE_FATAL as archive_sys::la_int64_t,
+    };
+
+    match reader.seek(position) {
+        Ok(position) => position as archive_sys::la_int64_t,
+        Err(e) => {
+            error!("Failed to seek archive source: {}", e);
+            unsafe { set_io_error(handle, &e) };
+
+            archive_sys::ARCHIVE_FATAL as archive_sys::la_int64_t
+        }
+    }
+}
@@ -0,0 +1,167 @@
+use std::ffi::{c_void, CString};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use archive_sys::archive;
+use log::error;
+
+use crate::source::set_io_error;
+
+/// Where an [`crate::ArchiveWriter`] writes the
+/// archive to, in the shape a write handle needs it.
+pub(crate) enum Output {
+    File(PathBuf),
+    Sink(Sink),
+}
+
+impl Output {
+    /// The path of the archive, when it is written
+    /// to a file
+    pub(crate) fn path(&self) -> Option<&Path> {
+        match self {
+            Self::File(path) => Some(path),
+            Self::Sink(_) => None,
+        }
+    }
+
+    /// Hands the output to `handle` and opens it,
+    /// returning the `libarchive` result code and
+    /// the name of the function that produced it
+    pub(crate) fn open(&self, handle: *mut archive) -> crate::error::Result<(i32, &'static str)> {
+        match self {
+            Self::File(path) => {
+                let filename = CString::new(path.as_os_str().as_encoded_bytes())?;
+                let ret =
+                    unsafe { archive_sys::archive_write_open_filename(handle, filename.as_ptr()) };
+
+                Ok((ret, "archive_write_open_filename"))
+            }
+            Self::Sink(sink) => {
+                let ret = unsafe {
+                    // Like regular files, memory needs no padding
+                    // after the last block. Other writers get the
+                    // full last block, as pipes and tapes expect
+                    if let Destination::Memory(_) = (*sink.0).writer {
+                        archive_sys::archive_write_set_bytes_in_last_block(handle, 1);
+                    }
+
+                    archive_sys::archive_write_open2(
+                        handle,
+                        sink.0 as *mut c_void,
+                        None,
+                        Some(write_callback),
+                        Some(close_callback),
+                        None,
+                    )
+                };
+
+                Ok((ret, "archive_write_open2"))
+            }
+        }
+    }
+}
+
+/// The client data handed to `libarchive` for outputs
+/// other than files.
+///
+/// It is kept behind a raw pointer, as `libarchive`
+/// holds on to it for as long as the handle is open.
+pub(crate) struct Sink(*mut SinkState);
+
+struct SinkState {
+    writer: Destination,
+}
+
+/// What a [`Sink`] writes to
+enum Destination {
+    Memory(Vec<u8>),
+    Writer(Box<dyn Write + Send>),
+}
+
+impl Write for Destination {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            Self::Memory(data) => data.write(buf),
+            Self::Writer(writer) => writer.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            Self::Memory(_) => Ok(()),
+            Self::Writer(writer) => writer.flush(),
+        }
+    }
+}
+
+impl Sink {
+    /// Writes into a growable buffer
+    pub(crate) fn memory() -> Self {
+        Self::new(Destination::Memory(Vec::new()))
+    }
+
+    /// Writes into `writer`
+    pub(crate) fn writer(writer: impl Write + Send + 'static) -> Self {
+        Self::new(Destination::Writer(Box::new(writer)))
+    }
+
+    fn new(writer: Destination) -> Self {
+        Self(Box::into_raw(Box::new(SinkState { writer })))
+    }
+
+    /// Takes the buffer written so far, if this sink
+    /// writes into memory. Must only be called once
+    /// the handle writing to it is closed.
+    pub(crate) fn take_memory(&self) -> Option<Vec<u8>> {
+        match unsafe { &mut (*self.0).writer } {
+            Destination::Memory(data) => Some(std::mem::take(data)),
+            Destination::Writer(_) => None,
+        }
+    }
+}
+
+// SAFETY: the state is only reachable through this pointer,
+// which is uniquely owned, and the writer inside it is `Send`
+unsafe impl Send for Sink {}
+
+impl Drop for Sink {
+    fn drop(&mut self) {
+        // Handles are always freed before their output,
+        // so nothing can be using the state anymore
+        drop(unsafe { Box::from_raw(self.0) });
+    }
+}
+
+unsafe extern "C" fn write_callback(
+    handle: *mut archive,
+    client_data: *mut c_void,
+    buffer: *const c_void,
+    length: usize,
+) -> archive_sys::la_ssize_t {
+    let state = unsafe { &mut *(client_data as *mut SinkState) };
+    let data = unsafe { std::slice::from_raw_parts(buffer as *const u8, length) };
+
+    match state.writer.write_all(data) {
+        Ok(()) => length as archive_sys::la_ssize_t,
+        Err(e) => {
+            error!("Failed to write to archive target: {}", e);
+            unsafe { set_io_error(handle, &e) };
+
+            archive_sys::ARCHIVE_FATAL as archive_sys::la_ssize_t
+        }
+    }
+}
+
+unsafe extern "C" fn close_callback(handle: *mut archive, client_data: *mut c_void) -> i32 {
+    let state = unsafe { &mut *(client_data as *mut SinkState) };
+
+    match state.writer.flush() {
+        Ok(()) => archive_sys::ARCHIVE_OK as i32,
+        Err(e) => {
+            error!("Failed to flush archive target: {}", e);
+            unsafe { set_io_error(handle, &e) };
+
+            archive_sys::ARCHIVE_FATAL
+        }
+    }
+}
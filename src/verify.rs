@@ -0,0 +1,333 @@
+use std::path::PathBuf;
+
+use log::{debug, warn};
+
+use crate::error::{Error, Result, Status};
+use crate::reader::{ArchiveEntry, ArchiveReader};
+
+/// The outcome of [`ArchiveReader::verify`]
+#[derive(Debug, Default)]
+pub struct VerifyReport {
+    /// Every entry whose header could be read, in
+    /// archive order
+    pub entries: Vec<EntryStatus>,
+    /// The headers that could not be read. When the last
+    /// one is fatal, the entries after it were not checked
+    pub errors: Vec<Error>,
+}
+
+impl VerifyReport {
+    /// Whether every header and every entry's data
+    /// could be read completely
+    pub fn is_ok(&self) -> bool {
+        self.errors.is_empty() && self.entries.iter().all(EntryStatus::is_ok)
+    }
+
+    /// The entries whose data could not be read
+    pub fn failures(&self) -> impl Iterator<Item = &EntryStatus> {
+        self.entries.iter().filter(|entry| !entry.is_ok())
+    }
+
+    /// The number of bytes of data that were read
+    pub fn bytes(&self) -> u64 {
+        self.entries.iter().map(|entry| entry.bytes).sum()
+    }
+}
+
+/// The state of a single entry, as checked by
+/// [`ArchiveReader::verify`]
+#[derive(Debug)]
+pub struct EntryStatus {
+    /// The path of the entry within the archive
+    pub path: PathBuf,
+    /// The number of bytes of data that were read
+    pub bytes: u64,
+    /// The size recorded in the entry's header, if any
+    pub size: Option<u64>,
+    /// Warnings reported for the header or the data.
+    /// These do not make the entry fail
+    pub warnings: Vec<String>,
+    /// What went wrong when reading the data, if
+    /// anything
+    pub problem: Option<Problem>,
+}
+
+impl EntryStatus {
+    /// Whether the entry's data could be read completely
+    pub fn is_ok(&self) -> bool {
+        self.problem.is_none()
+    }
+}
+
+/// Why an entry's data could not be read
+#[derive(Debug)]
+pub enum Problem {
+    /// The data ended before the size recorded in the
+    /// header. Carries the error `libarchive` reported,
+    /// or `None` if the data simply ran short
+    Truncated(Option<Error>),
+    /// A checksum of the data (e.g. a zip entry's CRC-32)
+    /// did not match
+    Checksum(Error),
+    /// Any other error, such as corrupt compressed data
+    /// or a missing passphrase
+    Error(Error),
+}
+
+/// Reads the data of every entry of `reader`, from the
+/// start of the archive
+pub(crate) fn verify(reader: &mut ArchiveReader) -> Result<VerifyReport> {
+    reader.rewind()?;
+
+    let mut report = VerifyReport::default();
+    let mut buffer = vec![0; reader.chunk_size.max(1)];
+
+    loop {
+        let mut entry = match reader.next_entry() {
+            Ok(Some(entry)) => entry,
+            Ok(None) => break,
+            Err(error) => {
+                let fatal = error.is_fatal();
+                report.errors.push(error);
+
+                if fatal {
+                    break;
+                }
+
+                continue;
+            }
+        };
+
+        let status = check(&mut entry, &mut buffer);
+        debug!("Verified `{}`: {:?}", status.path.display(), status.problem);
+
+        // A fatal error while reading data leaves nothing
+        // more to read
+        let fatal = matches!(
+            &status.problem,
+            Some(Problem::Truncated(Some(error)) | Problem::Checksum(error) | Problem::Error(error))
+                if error.is_fatal()
+        );
+
+        report.entries.push(status);
+
+        if fatal {
+            break;
+        }
+    }
+
+    Ok(report)
+}
+
+/// Reads `entry`'s data to the end
+fn check(entry: &mut ArchiveEntry<'_>, buffer: &mut [u8]) -> EntryStatus {
+    let info = entry.to_info();
+    let mut status = EntryStatus {
+        path: info.path,
+        bytes: 0,
+        size: info.size,
+        warnings: entry.warning().map(str::to_string).into_iter().collect(),
+        problem: None,
+    };
+
+    loop {
+        match entry.read_data(buffer) {
+            Ok(0) => break,
+            Ok(read) => status.bytes += read as u64,
+            Err(error) if error.status() == Some(Status::Warn) => {
+                status.warnings.push(error.to_string());
+            }
+            Err(error) => {
+                warn!("Failed to verify `{}`: {}", status.path.display(), error);
+                status.problem = Some(classify(error));
+
+                return status;
+            }
+        }
+    }
+
+    // Only regular files have their data stored; links
+    // and directories may still record a size
+    let stored = info.kind == crate::entry::EntryKind::File;
+    if stored && status.size.is_some_and(|size| status.bytes < size) {
+        status.problem = Some(Problem::Truncated(None));
+    }
+
+    status
+}
+
+/// What `libarchive` reports malformed archives with
+const ARCHIVE_ERRNO_FILE_FORMAT: i32 = libc::EILSEQ;
+/// What `libarchive` reports its other own errors with
+const ARCHIVE_ERRNO_MISC: i32 = -1;
+
+/// Sorts an error by what went wrong.
+///
+/// The error code tells failures of the input itself
+/// (e.g. `EIO`) apart from problems with the archive.
+/// `libarchive` reports truncated data and bad checksums
+/// alike with [`ARCHIVE_ERRNO_FILE_FORMAT`] or
+/// [`ARCHIVE_ERRNO_MISC`] though, so for these, this falls
+/// back to the wording of the message, as of `libarchive`
+/// 3.x (which the `libarchive_version` test checks for).
+fn classify(error: Error) -> Problem {
+    let Error::Archive { errno, message, .. } = &error else {
+        return Problem::Error(error);
+    };
+
+    if error.kind() == std::io::ErrorKind::UnexpectedEof {
+        return Problem::Truncated(Some(error));
+    }
+
+    if !matches!(*errno, ARCHIVE_ERRNO_FILE_FORMAT | ARCHIVE_ERRNO_MISC) {
+        return Problem::Error(error);
+    }
+
+    let message = message.to_lowercase();
+
+    if message.contains("truncated") || message.contains("premature end") {
+        Problem::Truncated(Some(error))
+    } else if ["crc", "checksum", "digest"]
+        .iter()
+        .any(|word| message.contains(word))
+    {
+        Problem::Checksum(error)
+    } else {
+        Problem::Error(error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::{ArchiveFilter, ArchiveFormat};
+    use crate::{ArchiveOptions, ArchiveWriter};
+    use std::path::Path;
+
+    /// Writes a few files in `format`, the last of
+    /// which spans many blocks
+    fn fixture(format: ArchiveFormat) -> Vec<u8> {
+        let options = ArchiveOptions::builder()
+            .filters([ArchiveFilter::None])
+            .formats([format])
+            .build();
+        let mut writer = ArchiveWriter::memory().handle_opts(options).open().unwrap();
+
+        writer.add_bytes("README.md", b"# archive\n").unwrap();
+        writer.add_bytes("LICENSE", b"MIT License\n").unwrap();
+        writer
+            .add_bytes("data.bin", &b"0123456789abcdef".repeat(4096))
+            .unwrap();
+
+        writer.finish_into_vec().unwrap()
+    }
+
+    #[test]
+    fn verify_intact() {
+        let mut reader = ArchiveReader::builder()
+            .source("archive.tar.gz")
+            .open()
+            .unwrap();
+        let report = reader.verify().unwrap();
+
+        assert!(report.is_ok(), "{report:?}");
+        assert!(report
+            .entries
+            .iter()
+            .any(|entry| entry.path == Path::new("README.md")));
+        assert_eq!(
+            report.bytes(),
+            report
+                .entries
+                .iter()
+                .filter_map(|entry| entry.size)
+                .sum::<u64>()
+        );
+    }
+
+    #[test]
+    fn verify_truncated() {
+        // Cut into the data of the last entry
+        let mut data = fixture(ArchiveFormat::TarPaxRestricted);
+        data.truncate(data.len() / 2);
+
+        let mut reader = ArchiveReader::builder().source(data).open().unwrap();
+        let report = reader.verify().unwrap();
+        dbg!(&report);
+
+        let failed: Vec<_> = report.failures().collect();
+        assert_eq!(failed.len(), 1);
+        assert_eq!(failed[0].path, Path::new("data.bin"));
+
+        let Some(Problem::Truncated(Some(Error::Archive { errno, .. }))) = &failed[0].problem
+        else {
+            panic!("not truncated: {:?}", failed[0].problem);
+        };
+        assert!(matches!(
+            *errno,
+            ARCHIVE_ERRNO_FILE_FORMAT | ARCHIVE_ERRNO_MISC
+        ));
+    }
+
+    #[test]
+    fn verify_checksum() {
+        let mut data = fixture(ArchiveFormat::Zip);
+
+        // Corrupt the CRC-32 recorded in the central directory
+        // for the first entry that is a file
+        let offsets: Vec<_> = data
+            .windows(4)
+            .enumerate()
+            .filter(|(_, window)| window == b"PK\x01\x02")
+            .map(|(offset, _)| offset)
+            .collect();
+
+        for offset in offsets {
+            let crc = offset + 16;
+
+            if data[crc..crc + 4] != [0; 4] {
+                data[crc] ^= 0xff;
+                break;
+            }
+        }
+
+        let mut reader = ArchiveReader::builder().source(data).open().unwrap();
+        let report = reader.verify().unwrap();
+        dbg!(&report);
+
+        let failed: Vec<_> = report.failures().collect();
+        assert_eq!(failed.len(), 1);
+        assert!(matches!(failed[0].problem, Some(Problem::Checksum(_))));
+    }
+
+    #[test]
+    fn libarchive_version() {
+        // `classify` relies on the wording of messages, which
+        // needs checking again for another major version
+        let version = unsafe { archive_sys::archive_version_number() };
+        assert_eq!(version / 1_000_000, 3, "libarchive {version}");
+
+        assert!(matches!(
+            classify(archive_error(
+                ARCHIVE_ERRNO_MISC,
+                "ZIP bad CRC: 0x0 should be 0x1"
+            )),
+            Problem::Checksum(_)
+        ));
+        assert!(matches!(
+            classify(archive_error(libc::EIO, "Truncated input file")),
+            Problem::Error(_)
+        ));
+    }
+
+    fn archive_error(errno: i32, message: &str) -> Error {
+        Error::Archive {
+            status: Status::Fatal,
+            errno,
+            kind: std::io::ErrorKind::Other,
+            message: message.to_string(),
+            operation: "archive_read_data",
+            path: None,
+        }
+    }
+}
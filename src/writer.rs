@@ -1,111 +1,1816 @@
 use std::cell::UnsafeCell;
-use std::ffi::CString;
+use std::ffi::{c_void, CString};
+use std::io::{Read, Seek, Write};
 use std::marker::PhantomData;
-use std::path::PathBuf;
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+use std::time::SystemTime;
 
-use bon::Builder;
+use bon::{bon, Builder};
 
 use archive_sys::archive;
-use log::warn;
+use log::{debug, error, warn};
 
-use crate::core::ArchiveFilter;
-use crate::error::Result;
+use crate::core::{ArchiveFilter, ArchiveFormat};
+use crate::entry::{path_of, EntryHeader, EntryInfo, EntryKind, OwnedEntry};
+use crate::error::{Error, Result};
+use crate::target::{Output, Sink};
 use crate::ArchiveOptions;
 
-#[derive(Builder)]
+/// The size of the chunks file data is copied in
+const COPY_CHUNK_SIZE: usize = 64 * 1024;
+
+/// The default for how much of an entry of unknown size
+/// is held in memory before spooling it to disk
+const DEFAULT_SPOOL_THRESHOLD: usize = 8 * 1024 * 1024;
+
+/// A handle to an archive opened for writing.
+///
+/// Writers are created through [`ArchiveWriter::builder`],
+/// whose `open()` method returns an already opened writer.
+/// Entries are written in the order they are added, and the
+/// archive is finalised with [`ArchiveWriter::finish`], or
+/// when the writer is dropped.
+///
+/// ```no_run
+/// use archive::entry::EntryHeader;
+/// use archive::ArchiveWriter;
+///
+/// let mut writer = ArchiveWriter::builder().path("out.tar.gz").open()?;
+///
+/// writer.add_dir("config")?;
+/// writer.add_bytes("config/app.toml", b"debug = false\n")?;
+/// writer.add_symlink("app.toml", "config/app.toml")?;
+///
+/// let log = std::fs::File::open("app.log")?;
+/// let header = EntryHeader::builder()
+///     .path("logs/app.log")
+///     .size(log.metadata()?.len())
+///     .mode(0o600)
+///     .build();
+/// writer.add_entry(&header, log)?;
+///
+/// writer.finish()?;
+/// # Ok::<(), archive::error::Error>(())
+/// ```
+///
+/// Like [`crate::reader::ArchiveReader`], writers are [`Send`]
+/// but not [`Sync`].
 pub struct ArchiveWriter {
-    #[builder(skip = std::ptr::null_mut())]
     handle: *mut archive,
 
-    #[builder(into)]
-    path: PathBuf,
-
-    #[builder(default)]
+    output: Output,
     handle_opts: ArchiveOptions,
+    spool_threshold: usize,
 
-    #[builder(skip)]
     _marker: PhantomData<UnsafeCell<archive>>,
 }
 
+// SAFETY: see `ArchiveReader`; the handle is exclusively
+// owned and has no affinity to the thread that created it.
+unsafe impl Send for ArchiveWriter {}
+
+#[bon]
 impl ArchiveWriter {
-    pub fn open(&mut self) -> Result<()> {
-        if !self.handle.is_null() {
-            warn!("Attempting to re-open an already opened archive!");
-            return Err(crate::error::Error::AlreadyOpen);
-        }
+    /// Creates (or truncates) the archive at `path`.
+    ///
+    /// Unless given in `handle_opts`, the format and filter
+    /// are picked from the extension of `path`, e.g.
+    /// `.tar.gz`, `.tgz`, `.tar.zst`, `.zip`, `.7z`,
+    /// `.cpio`, `.iso` or `.a`. An explicit format and an
+    /// extension `libarchive` does not know leave the
+    /// archive uncompressed.
+    ///
+    /// # Errors
+    ///
+    /// Fails with [`crate::error::Error::UnknownExtension`]
+    /// when no format is given and none can be picked
+    /// from `path`
+    #[builder(finish_fn = open)]
+    pub fn new(
+        #[builder(into)] path: PathBuf,
+
+        /// Set of options to be passed for the
+        /// handle. Refer to [`crate::core::ArchiveOptions`]
+        /// for more information
+        #[builder(default)]
+        handle_opts: ArchiveOptions,
+
+        /// How many bytes of an entry of unknown size
+        /// [`ArchiveWriter::add_stream`] holds in memory
+        /// before spooling it to a temporary file.
+        /// Defaults to 8 MiB
+        #[builder(default = DEFAULT_SPOOL_THRESHOLD)]
+        spool_threshold: usize,
+    ) -> Result<Self> {
+        Self::from_output(Output::File(path), handle_opts, spool_threshold)
+    }
+
+    /// Creates an archive in memory, which is returned by
+    /// [`ArchiveWriter::finish_into_vec`].
+    ///
+    /// As there is no file name to pick it from, the format
+    /// has to be given in `handle_opts`. Without a filter,
+    /// the archive is left uncompressed.
+    ///
+    /// ```no_run
+    /// use archive::core::{ArchiveFormat, ArchiveOptions};
+    /// use archive::ArchiveWriter;
+    ///
+    /// let options = ArchiveOptions::builder().formats([ArchiveFormat::Zip]).build();
+    /// let mut writer = ArchiveWriter::memory().handle_opts(options).open()?;
+    ///
+    /// writer.add_bytes("hello.txt", b"Hello, world!\n")?;
+    /// let zip: Vec<u8> = writer.finish_into_vec()?;
+    /// # Ok::<(), archive::error::Error>(())
+    /// ```
+    #[builder(finish_fn = open)]
+    pub fn memory(
+        /// Set of options to be passed for the
+        /// handle. Refer to [`crate::core::ArchiveOptions`]
+        /// for more information
+        #[builder(default)]
+        handle_opts: ArchiveOptions,
 
+        /// How many bytes of an entry of unknown size
+        /// [`ArchiveWriter::add_stream`] holds in memory
+        /// before spooling it to a temporary file.
+        /// Defaults to 8 MiB
+        #[builder(default = DEFAULT_SPOOL_THRESHOLD)]
+        spool_threshold: usize,
+    ) -> Result<Self> {
+        Self::from_output(Output::Sink(Sink::memory()), handle_opts, spool_threshold)
+    }
+
+    /// Creates an archive written to `target`, which can be
+    /// anything implementing [`Write`], such as stdout, a
+    /// socket or a hasher. `target` is flushed when the
+    /// archive is finalised, and dropped along with the
+    /// writer.
+    ///
+    /// Errors raised by `target` fail the operation that
+    /// was writing, carrying the [`std::io::Error`]'s
+    /// message and, for OS errors, its kind.
+    ///
+    /// As there is no file name to pick it from, the format
+    /// has to be given in `handle_opts`. Without a filter,
+    /// the archive is left uncompressed.
+    ///
+    /// ```no_run
+    /// use archive::core::{ArchiveFilter, ArchiveFormat, ArchiveOptions};
+    /// use archive::ArchiveWriter;
+    ///
+    /// let options = ArchiveOptions::builder()
+    ///     .filters([ArchiveFilter::Gzip])
+    ///     .formats([ArchiveFormat::TarPaxRestricted])
+    ///     .build();
+    /// let mut writer = ArchiveWriter::stream()
+    ///     .target(std::io::stdout())
+    ///     .handle_opts(options)
+    ///     .open()?;
+    ///
+    /// writer.add_path("logs", &Default::default())?;
+    /// writer.finish()?;
+    /// # Ok::<(), archive::error::Error>(())
+    /// ```
+    ///
+    /// # Seekable targets
+    ///
+    /// There is no variant taking a `Write + Seek` target,
+    /// as it could not be put to use: `archive_write_open2`
+    /// only accepts open, write, close and free callbacks,
+    /// and none of `libarchive`'s format writers ever go
+    /// back in their output, not even for files. Zip
+    /// entries are therefore never backpatched; when their
+    /// compressed size is not known up front, it follows
+    /// their data in a data descriptor.
+    #[builder(finish_fn = open)]
+    pub fn stream<W: Write + Send + 'static>(
+        target: W,
+
+        /// Set of options to be passed for the
+        /// handle. Refer to [`crate::core::ArchiveOptions`]
+        /// for more information
+        #[builder(default)]
+        handle_opts: ArchiveOptions,
+
+        /// How many bytes of an entry of unknown size
+        /// [`ArchiveWriter::add_stream`] holds in memory
+        /// before spooling it to a temporary file.
+        /// Defaults to 8 MiB
+        #[builder(default = DEFAULT_SPOOL_THRESHOLD)]
+        spool_threshold: usize,
+    ) -> Result<Self> {
+        Self::from_output(
+            Output::Sink(Sink::writer(target)),
+            handle_opts,
+            spool_threshold,
+        )
+    }
+}
+
+impl ArchiveWriter {
+    /// Opens a writer on `output`
+    fn from_output(
+        output: Output,
+        handle_opts: ArchiveOptions,
+        spool_threshold: usize,
+    ) -> Result<Self> {
         let handle = unsafe { archive_sys::archive_write_new() };
         if handle.is_null() {
             return Err(crate::error::Error::Initialization);
         }
 
-        self.handle = handle;
-        self.set_options()?;
-        self.open_file()?;
+        // From here on, `Drop` frees the handle if
+        // anything goes wrong
+        let writer = Self {
+            handle,
+            output,
+            handle_opts,
+            spool_threshold,
+            _marker: PhantomData,
+        };
+
+        writer.set_options()?;
+        writer.open_output()?;
+
+        Ok(writer)
+    }
+
+    /// Finalises the archive, writing any trailers
+    /// and flushing buffered data, then frees the
+    /// handle.
+    ///
+    /// This is called on `Drop`, but errors can
+    /// only be logged there; call this explicitly
+    /// to make sure the archive was written
+    /// completely.
+    ///
+    /// The handle is freed even when finalising
+    /// fails.
+    pub fn finish(mut self) -> Result<()> {
+        self.free()
+    }
+
+    /// Finalises the archive like [`ArchiveWriter::finish`],
+    /// returning the archive written by a writer created
+    /// through [`ArchiveWriter::memory`].
+    ///
+    /// # Errors
+    ///
+    /// Fails with [`std::io::ErrorKind::Unsupported`] when
+    /// the archive was not written to memory; it is still
+    /// finalised in that case
+    pub fn finish_into_vec(mut self) -> Result<Vec<u8>> {
+        self.free()?;
+
+        match &self.output {
+            Output::Sink(sink) => sink.take_memory(),
+            Output::File(_) => None,
+        }
+        .ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "the archive was not written to memory",
+            )
+            .into()
+        })
+    }
+
+    /// Writes an entry described by `header`, followed by
+    /// its data, read out of `data`.
+    ///
+    /// Only regular files have data; `data` is not read
+    /// for any other kind of entry, so [`std::io::empty`]
+    /// can be passed for those.
+    ///
+    /// # Errors
+    ///
+    /// Fails with [`std::io::ErrorKind::InvalidInput`] when
+    /// a regular file's header has no size and the format
+    /// needs one (refer to [`ArchiveWriter::add_stream`]),
+    /// or when `data` holds more than that size, and with
+    /// [`std::io::ErrorKind::UnexpectedEof`] when it holds
+    /// less. In both cases, the entry was already written
+    /// with the size from its header
+    pub fn add_entry<R: Read>(&mut self, header: &EntryHeader, mut data: R) -> Result<()> {
+        let mut entry = self.start_entry(header)?;
+
+        if header.kind == EntryKind::File {
+            entry.copy_from(&mut data)?;
+        }
+
+        entry.finish()
+    }
+
+    /// Writes the header of an entry, returning a writer
+    /// for its data, e.g. to stream generated content into
+    /// the archive without buffering it first.
+    ///
+    /// Exactly the size declared in `header` has to be
+    /// written before calling [`EntryWriter::finish`].
+    /// Entries other than regular files take no data.
+    ///
+    /// Zip archives record sizes after the data, so the
+    /// size of their regular files can be left out.
+    ///
+    /// ```no_run
+    /// use std::io::Write;
+    /// use archive::entry::EntryHeader;
+    /// use archive::ArchiveWriter;
+    ///
+    /// let report = "total: 42\n";
+    ///
+    /// let mut writer = ArchiveWriter::builder().path("out.tar").open()?;
+    /// let header = EntryHeader::builder()
+    ///     .path("report.txt")
+    ///     .size(report.len() as u64)
+    ///     .build();
+    ///
+    /// let mut entry = writer.start_entry(&header)?;
+    /// write!(entry, "{}", report)?;
+    /// entry.finish()?;
+    /// # Ok::<(), Box<dyn std::error::Error>>(())
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// Fails with [`std::io::ErrorKind::InvalidInput`] when
+    /// a regular file's header has no size and the format
+    /// needs one
+    pub fn start_entry(&mut self, header: &EntryHeader) -> Result<EntryWriter<'_>> {
+        let path = header.path();
+
+        if header.kind == EntryKind::File && header.size.is_none() && !self.streams_unsized() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!(
+                    "the size of `{}` was not declared, which the format needs",
+                    path.display()
+                ),
+            )
+            .into());
+        }
+
+        debug!("Adding `{}`", path.display());
+        self.write_header(&header.to_raw()?, path)
+    }
+
+    /// Writes a regular file described by `header`, whose
+    /// data is read out of `data` until it ends, without
+    /// knowing its size beforehand. The size in `header`
+    /// is ignored. Returns the size of the entry.
+    ///
+    /// Zip archives record sizes after the data, which is
+    /// then streamed directly. Other formats (e.g. tar and
+    /// cpio) need the size in the header, so data is
+    /// spooled first: in memory up to the writer's
+    /// `spool_threshold`, then to an anonymous temporary
+    /// file, which is deleted afterwards.
+    ///
+    /// ```no_run
+    /// use archive::entry::EntryHeader;
+    /// use archive::ArchiveWriter;
+    ///
+    /// let mut writer = ArchiveWriter::builder().path("dump.tar.xz").open()?;
+    /// let mut dump = std::process::Command::new("pg_dump")
+    ///     .stdout(std::process::Stdio::piped())
+    ///     .spawn()?;
+    ///
+    /// let header = EntryHeader::builder().path("db.sql").build();
+    /// writer.add_stream(&header, dump.stdout.take().unwrap())?;
+    /// # Ok::<(), Box<dyn std::error::Error>>(())
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// Fails with [`std::io::ErrorKind::InvalidInput`] when
+    /// `header` is not a regular file, and when `data` or
+    /// the temporary file cannot be read
+    pub fn add_stream<R: Read>(&mut self, header: &EntryHeader, mut data: R) -> Result<u64> {
+        if header.kind != EntryKind::File {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!(
+                    "only regular files have data, `{}` is a {:?}",
+                    header.path().display(),
+                    header.kind
+                ),
+            )
+            .into());
+        }
+
+        let mut header = header.clone();
+
+        if self.streams_unsized() {
+            header.size = None;
+
+            let mut entry = self.start_entry(&header)?;
+            entry.copy_from(&mut data)?;
+            let size = entry.written();
+            entry.finish()?;
+
+            return Ok(size);
+        }
+
+        let mut buffer = Vec::new();
+        (&mut data)
+            .take(self.spool_threshold as u64 + 1)
+            .read_to_end(&mut buffer)?;
+
+        if buffer.len() <= self.spool_threshold {
+            header.size = Some(buffer.len() as u64);
+            self.add_entry(&header, buffer.as_slice())?;
+
+            return Ok(buffer.len() as u64);
+        }
+
+        debug!("Spooling `{}` to a temporary file", header.path().display());
+
+        let mut spool = tempfile::tempfile()?;
+        spool.write_all(&buffer)?;
+        let size = buffer.len() as u64 + std::io::copy(&mut data, &mut spool)?;
+        drop(buffer);
+
+        spool.rewind()?;
+        header.size = Some(size);
+        self.add_entry(&header, spool)?;
+
+        Ok(size)
+    }
+
+    /// Writes the file or directory at `path` into the
+    /// archive, along with everything below it, much like
+    /// `bsdtar -c` would.
+    ///
+    /// Files are read with `libarchive`'s own directory
+    /// walker, which records everything the format can
+    /// hold: permissions, owner and group ids and names,
+    /// timestamps, extended attributes, ACLs, file flags,
+    /// and holes in sparse files. Symlinks are stored
+    /// rather than followed unless [`AddPathOptions`] says
+    /// otherwise. The archive itself is skipped if it lies
+    /// within `path`.
+    ///
+    /// ```no_run
+    /// use archive::writer::AddPathOptions;
+    /// use archive::ArchiveWriter;
+    ///
+    /// let mut writer = ArchiveWriter::builder().path("site.tar.zst").open()?;
+    /// let options = AddPathOptions::builder()
+    ///     .name("site")
+    ///     .filter(|file| file.path.file_name().is_none_or(|name| name != "node_modules"))
+    ///     .build();
+    ///
+    /// writer.add_path("/srv/www/site", &options)?;
+    /// writer.finish()?;
+    /// # Ok::<(), archive::error::Error>(())
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// Fails on the first file that cannot be read or
+    /// written. The files before it remain in the archive
+    pub fn add_path<P: AsRef<Path>>(&mut self, path: P, options: &AddPathOptions) -> Result<()> {
+        let path = path.as_ref();
+        let name = options.name.clone().unwrap_or_else(|| entry_name(path));
+
+        let disk = DiskReader::new()?;
+
+        // `libarchive` refuses to add an archive to itself
+        let output = self
+            .output
+            .path()
+            .and_then(|output| disk.entry(output, output).ok())
+            .map(|output| unsafe {
+                (
+                    archive_sys::archive_entry_dev(output.0) as u64,
+                    archive_sys::archive_entry_ino64(output.0) as u64,
+                )
+            });
+
+        disk.configure(options)?;
+        disk.open(path)?;
+
+        let entry = OwnedEntry::new()?;
+        let mut zeros = Vec::new();
+
+        while disk.next_header(&entry)? {
+            let source = unsafe { path_of(archive_sys::archive_entry_pathname(entry.0)) }
+                .unwrap_or_default();
+            let info = unsafe { EntryInfo::from_raw(entry.0, source.clone()) };
+
+            if output.is_some() && output == info.dev.zip(info.ino) {
+                debug!("Skipping the archive itself at `{}`", source.display());
+                continue;
+            }
+
+            if let Some(filter) = &options.filter {
+                if !filter(&info) {
+                    debug!("Filtered out `{}`", source.display());
+                    continue;
+                }
+            }
+
+            disk.descend()?;
+
+            let relative = source.strip_prefix(path).unwrap_or(&source);
+            let target = if relative.as_os_str().is_empty() {
+                name.clone()
+            } else {
+                name.join(relative)
+            };
+
+            debug!("Adding `{}` as `{}`", source.display(), target.display());
+
+            let target_c = CString::new(target.as_os_str().as_encoded_bytes())?;
+            unsafe { archive_sys::archive_entry_copy_pathname(entry.0, target_c.as_ptr()) };
+
+            let mut writer = self.write_header(&entry, &target)?;
+
+            if info.kind == EntryKind::File {
+                disk.copy_data(&mut writer, &source, &mut zeros)?;
+            }
+
+            writer.finish()?;
+        }
 
         Ok(())
     }
 
-    fn open_file(&mut self) -> Result<()> {
-        let open_result = unsafe {
-            let filename = CString::new(self.path.as_mut_os_string().as_encoded_bytes())?.into_raw()
-                as *const i8;
+    /// Writes a regular file holding `data`, modified now
+    pub fn add_bytes<P: Into<PathBuf>>(&mut self, path: P, data: &[u8]) -> Result<()> {
+        let header = EntryHeader::builder()
+            .path(path)
+            .size(data.len() as u64)
+            .mtime(SystemTime::now())
+            .build();
+
+        self.add_entry(&header, data)
+    }
+
+    /// Writes a directory, modified now
+    pub fn add_dir<P: Into<PathBuf>>(&mut self, path: P) -> Result<()> {
+        let header = EntryHeader::builder()
+            .path(path)
+            .kind(EntryKind::Directory)
+            .mtime(SystemTime::now())
+            .build();
+
+        self.add_entry(&header, std::io::empty())
+    }
+
+    /// Writes a symlink at `path`, pointing to `target`
+    pub fn add_symlink<P: Into<PathBuf>, T: Into<PathBuf>>(
+        &mut self,
+        path: P,
+        target: T,
+    ) -> Result<()> {
+        let header = EntryHeader::builder()
+            .path(path)
+            .kind(EntryKind::Symlink)
+            .symlink(target)
+            .mtime(SystemTime::now())
+            .build();
+
+        self.add_entry(&header, std::io::empty())
+    }
+
+    /// Finalises the archive and frees the handle,
+    /// nulling it so that it is only ever freed once.
+    fn free(&mut self) -> Result<()> {
+        let handle = std::mem::replace(&mut self.handle, std::ptr::null_mut());
+
+        if handle.is_null() {
+            return Ok(());
+        }
+
+        let ret = unsafe { archive_sys::archive_write_close(handle) };
+        let result = if ret != archive_sys::ARCHIVE_OK as i32 {
+            Err(crate::error::Error::from_handle(
+                handle,
+                ret,
+                "archive_write_close",
+            ))
+        } else {
+            Ok(())
+        };
+
+        let ret = unsafe { archive_sys::archive_write_free(handle) };
+
+        if ret != archive_sys::ARCHIVE_OK as i32 {
+            // The handle is gone at this point, so there
+            // is no error message to retrieve
+            error!("archive_write_free failed with code {}", ret);
+        }
+
+        result
+    }
+
+    /// Whether the format can write regular files whose
+    /// size is not known until their data ends
+    fn streams_unsized(&self) -> bool {
+        let code = unsafe { archive_sys::archive_format(self.handle) };
+
+        ArchiveFormat::from_code(code).map(ArchiveFormat::family) == Some(ArchiveFormat::Zip)
+    }
+
+    /// Writes the header of `entry`, named `path`
+    fn write_header(&mut self, entry: &OwnedEntry, path: &Path) -> Result<EntryWriter<'_>> {
+        let ret = unsafe { archive_sys::archive_write_header(self.handle, entry.0) };
+        check(self.handle, ret, "archive_write_header", path)?;
 
-            archive_sys::archive_write_open_filename(self.handle, filename)
+        // Only regular files that are not hardlinks carry data,
+        // whose size may be left for the format to record
+        let size = unsafe {
+            let file_type = archive_sys::archive_entry_filetype(entry.0);
+            let hardlink = archive_sys::archive_entry_hardlink(entry.0);
+
+            if file_type != archive_sys::S_IFREG || !hardlink.is_null() {
+                Some(0)
+            } else if archive_sys::archive_entry_size_is_set(entry.0) != 0 {
+                Some(archive_sys::archive_entry_size(entry.0).max(0) as u64)
+            } else {
+                None
+            }
         };
 
+        Ok(EntryWriter {
+            writer: self,
+            path: path.to_path_buf(),
+            size,
+            written: 0,
+            finished: false,
+        })
+    }
+
+    fn open_output(&self) -> Result<()> {
+        let (open_result, operation) = self.output.open(self.handle)?;
+
         if open_result != archive_sys::ARCHIVE_OK as i32 {
-            return Err(crate::error::Error::Archive {
-                message: crate::get_error(self.handle, open_result).to_string(),
-                code: open_result,
-            });
+            return Err(crate::error::Error::from_handle(
+                self.handle,
+                open_result,
+                operation,
+            ));
         }
 
         Ok(())
     }
 
-    fn set_options(&mut self) -> Result<()> {
-        let filter_result = unsafe {
-            archive_sys::archive_write_add_filter(self.handle, self.handle_opts.filter as i32)
+    /// Sets the format and filter of the handle. When
+    /// either is [`ArchiveFormat::Auto`] (or
+    /// [`ArchiveFilter::Auto`]), it is picked from the
+    /// extension of the archive's path
+    fn set_options(&self) -> Result<()> {
+        let filter = self.handle_opts.single_filter()?;
+        let format = self.handle_opts.single_format()?;
+
+        if filter == ArchiveFilter::Program {
+            return Err(Error::UnsupportedFilter(filter));
+        }
+
+        let by_ext = match self.output.path() {
+            Some(path) if filter == ArchiveFilter::Auto || format == ArchiveFormat::Auto => {
+                resolve_ext(path)?
+            }
+            _ => None,
         };
 
-        if filter_result != archive_sys::ARCHIVE_OK as i32 {
-            return Err(crate::error::Error::Archive {
-                message: crate::get_error(self.handle, filter_result).to_string(),
-                code: filter_result,
-            });
+        let format = match (format, by_ext) {
+            (ArchiveFormat::Auto, Some((format, _))) => format,
+            (ArchiveFormat::Auto, None) => {
+                return Err(match self.output.path() {
+                    Some(path) => Error::UnknownExtension(path.to_path_buf()),
+                    None => Error::FormatRequired,
+                })
+            }
+            (format, _) => format as i32,
+        };
+
+        // An explicit format with no (known) extension
+        // leaves the archive uncompressed
+        let filter = match (filter, by_ext) {
+            (ArchiveFilter::Auto, by_ext) => by_ext.and_then(|(_, filter)| filter),
+            (filter, _) => Some(filter as i32),
+        };
+
+        if let Some(filter) = filter {
+            let filter_result =
+                unsafe { archive_sys::archive_write_add_filter(self.handle, filter) };
+
+            if filter_result != archive_sys::ARCHIVE_OK as i32 {
+                return Err(crate::error::Error::from_handle(
+                    self.handle,
+                    filter_result,
+                    "archive_write_add_filter",
+                ));
+            }
         }
 
-        let format_result = unsafe {
-            archive_sys::archive_write_set_format(self.handle, self.handle_opts.format as i32)
+        // `archive_write_set_format` does not know the ar
+        // variants, which have functions of their own
+        let (format_result, operation) = unsafe {
+            match format as u32 {
+                archive_sys::ARCHIVE_FORMAT_AR | archive_sys::ARCHIVE_FORMAT_AR_GNU => (
+                    archive_sys::archive_write_set_format_ar_svr4(self.handle),
+                    "archive_write_set_format_ar_svr4",
+                ),
+                archive_sys::ARCHIVE_FORMAT_AR_BSD => (
+                    archive_sys::archive_write_set_format_ar_bsd(self.handle),
+                    "archive_write_set_format_ar_bsd",
+                ),
+                _ => (
+                    archive_sys::archive_write_set_format(self.handle, format),
+                    "archive_write_set_format",
+                ),
+            }
         };
 
         if format_result != archive_sys::ARCHIVE_OK as i32 {
-            return Err(crate::error::Error::Archive {
-                message: crate::get_error(self.handle, format_result).to_string(),
-                code: format_result,
-            });
+            return Err(crate::error::Error::from_handle(
+                self.handle,
+                format_result,
+                operation,
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+/// Looks up the format and filter codes `libarchive`
+/// associates with the extension of `path`, e.g. `.tgz`
+/// or `.tar.zst`, using a scratch handle. The filter is
+/// `None` for uncompressed formats such as `.zip`
+fn resolve_ext(path: &Path) -> Result<Option<(i32, Option<i32>)>> {
+    let filename = CString::new(path.as_os_str().as_encoded_bytes())?;
+
+    let handle = unsafe { archive_sys::archive_write_new() };
+    if handle.is_null() {
+        return Err(Error::Initialization);
+    }
+
+    let resolved = unsafe {
+        let ret = archive_sys::archive_write_set_format_filter_by_ext(handle, filename.as_ptr());
+
+        if ret == archive_sys::ARCHIVE_OK as i32 {
+            let filter = (archive_sys::archive_filter_count(handle) > 0)
+                .then(|| archive_sys::archive_filter_code(handle, 0));
+
+            Some((archive_sys::archive_format(handle), filter))
+        } else {
+            debug!(
+                "No format for `{}`: {}",
+                path.display(),
+                crate::get_error(handle, ret)
+            );
+
+            None
+        }
+    };
+
+    unsafe { archive_sys::archive_write_free(handle) };
+
+    Ok(resolved)
+}
+
+/// The data of an entry being written, borrowed from the
+/// [`ArchiveWriter`] it is written to. Returned by
+/// [`ArchiveWriter::start_entry`].
+///
+/// Writes past the declared size fail with
+/// [`std::io::ErrorKind::InvalidInput`]. The entry is
+/// finished by [`EntryWriter::finish`], or when dropped,
+/// which can only log errors.
+pub struct EntryWriter<'a> {
+    writer: &'a mut ArchiveWriter,
+    path: PathBuf,
+    /// The declared size, if any
+    size: Option<u64>,
+    written: u64,
+    finished: bool,
+}
+
+impl EntryWriter<'_> {
+    /// The number of bytes written so far
+    pub fn written(&self) -> u64 {
+        self.written
+    }
+
+    /// The number of bytes left to write, or `None` when
+    /// the entry's size was not declared
+    pub fn remaining(&self) -> Option<u64> {
+        self.size.map(|size| size - self.written)
+    }
+
+    /// Finishes the entry, so that the next one can
+    /// be written.
+    ///
+    /// # Errors
+    ///
+    /// Fails with [`std::io::ErrorKind::UnexpectedEof`]
+    /// when less than the declared size was written. The
+    /// entry is padded to its size regardless
+    pub fn finish(mut self) -> Result<()> {
+        self.close()
+    }
+
+    fn close(&mut self) -> Result<()> {
+        if std::mem::replace(&mut self.finished, true) {
+            return Ok(());
+        }
+
+        let handle = self.writer.handle;
+        let ret = unsafe { archive_sys::archive_write_finish_entry(handle) };
+        check(handle, ret, "archive_write_finish_entry", &self.path)?;
+
+        if let Some(size) = self.size.filter(|size| self.written < *size) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                format!(
+                    "data of `{}` ended after {} of its {} bytes",
+                    self.path.display(),
+                    self.written,
+                    size
+                ),
+            )
+            .into());
+        }
+
+        Ok(())
+    }
+
+    /// Writes as much of `buf` as the declared size
+    /// allows, returning the number of bytes written
+    pub(crate) fn write_data(&mut self, buf: &[u8]) -> Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        let remaining = self.remaining().unwrap_or(u64::MAX);
+
+        if remaining == 0 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!(
+                    "data of `{}` is larger than its declared size of {} bytes",
+                    self.path.display(),
+                    self.written
+                ),
+            )
+            .into());
+        }
+
+        let len = buf.len().min(remaining.try_into().unwrap_or(usize::MAX));
+        let written = unsafe {
+            archive_sys::archive_write_data(self.writer.handle, buf.as_ptr() as *const c_void, len)
+        };
+
+        if written < 0 {
+            return Err(Error::from_handle(
+                self.writer.handle,
+                written as i32,
+                "archive_write_data",
+            )
+            .with_path(&self.path));
+        }
+
+        self.written += written as u64;
+        Ok(written as usize)
+    }
+
+    /// Writes `len` zero bytes, reusing `zeros` as the
+    /// source
+    pub(crate) fn write_zeros(&mut self, mut len: u64, zeros: &mut Vec<u8>) -> Result<()> {
+        if len > 0 && zeros.is_empty() {
+            zeros.resize(COPY_CHUNK_SIZE, 0);
+        }
+
+        while len > 0 {
+            let chunk = len.min(zeros.len() as u64) as usize;
+            len -= self.write_data(&zeros[..chunk])? as u64;
+        }
+
+        Ok(())
+    }
+
+    /// Writes everything `data` holds
+    pub(crate) fn copy_from(&mut self, data: &mut impl Read) -> Result<()> {
+        let mut buffer = vec![0; COPY_CHUNK_SIZE];
+
+        loop {
+            let mut chunk = match data.read(&mut buffer) {
+                Ok(0) => return Ok(()),
+                Ok(read) => &buffer[..read],
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e.into()),
+            };
+
+            while !chunk.is_empty() {
+                let written = self.write_data(chunk)?;
+                chunk = &chunk[written..];
+            }
+        }
+    }
+}
+
+impl Write for EntryWriter<'_> {
+    /// Writes this entry's data. Errors reported by
+    /// `libarchive` are converted from
+    /// [`crate::error::Error::Archive`], and carry
+    /// this entry's path.
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        Ok(self.write_data(buf)?)
+    }
+
+    /// Does nothing, as `libarchive` flushes data by
+    /// blocks on its own
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for EntryWriter<'_> {
+    fn drop(&mut self) {
+        if let Err(e) = self.close() {
+            error!("Failed to finish entry: {}", e);
+        }
+    }
+}
+
+/// Turns a `libarchive` result into an error for
+/// `path`, logging warnings
+fn check(handle: *mut archive, ret: i32, operation: &'static str, path: &Path) -> Result<()> {
+    if ret == archive_sys::ARCHIVE_WARN {
+        warn!(
+            "{} for `{}`: {}",
+            operation,
+            path.display(),
+            crate::get_error(handle, ret)
+        );
+    } else if ret != archive_sys::ARCHIVE_OK as i32 {
+        return Err(Error::from_handle(handle, ret, operation).with_path(path));
+    }
+
+    Ok(())
+}
+
+/// A callback deciding which files [`ArchiveWriter::add_path`]
+/// archives
+pub type PathFilter = Arc<dyn Fn(&EntryInfo) -> bool + Send + Sync>;
+
+/// Options for [`ArchiveWriter::add_path`]
+#[derive(Builder, Clone)]
+pub struct AddPathOptions {
+    #[builder(into)]
+    /// The path of the tree within the archive. Defaults
+    /// to the path on disk, without its root and any `.`
+    /// or `..` components, e.g. `/srv/www` is archived as
+    /// `srv/www`
+    pub(crate) name: Option<PathBuf>,
+
+    #[builder(with = |filter: impl Fn(&EntryInfo) -> bool + Send + Sync + 'static| Arc::new(filter) as PathFilter)]
+    /// Called with the metadata of every file found, whose
+    /// `path` is the one on disk. Files for which it
+    /// returns `false` are left out, along with everything
+    /// below them for directories
+    pub(crate) filter: Option<PathFilter>,
+
+    #[builder(default)]
+    /// How symlinks are handled. Defaults to
+    /// [`Symlinks::Physical`]
+    pub(crate) symlinks: Symlinks,
+
+    #[builder(default)]
+    /// Whether to stay on the filesystem `path` is on,
+    /// leaving out the contents of directories that other
+    /// filesystems are mounted on (like `--one-file-system`).
+    /// Defaults to `false`
+    pub(crate) one_file_system: bool,
+
+    #[builder(default)]
+    /// Whether to leave out files marked with the `nodump`
+    /// flag (see `chattr(1)` and `chflags(1)`). Defaults
+    /// to `false`
+    pub(crate) honor_nodump: bool,
+
+    #[builder(default)]
+    /// Whether to restore the access time of files after
+    /// reading them, so that archiving leaves no trace.
+    /// Defaults to `false`
+    pub(crate) restore_atime: bool,
+}
+
+/// How [`ArchiveWriter::add_path`] handles symlinks, named
+/// after the flags `tar` uses for them
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Symlinks {
+    /// Stores symlinks as links (`-P`)
+    #[default]
+    Physical,
+    /// Follows every symlink, storing what it points to
+    /// instead (`-L`)
+    Logical,
+    /// Follows the path given to
+    /// [`ArchiveWriter::add_path`] if it is a symlink,
+    /// and stores any other symlink as a link (`-H`)
+    CommandLine,
+}
+
+impl Default for AddPathOptions {
+    fn default() -> Self {
+        Self::builder().build()
+    }
+}
+
+/// Names a path given on the command line the way `tar`
+/// would, keeping it relative to the archive's root
+fn entry_name(path: &Path) -> PathBuf {
+    let name: PathBuf = path
+        .components()
+        .filter(|component| matches!(component, Component::Normal(_)))
+        .collect();
+
+    if name.as_os_str().is_empty() {
+        PathBuf::from(".")
+    } else {
+        name
+    }
+}
+
+/// A handle reading entries' metadata off disk
+pub(crate) struct DiskReader(*mut archive);
+
+impl DiskReader {
+    pub(crate) fn new() -> Result<Self> {
+        let handle = unsafe { archive_sys::archive_read_disk_new() };
+        if handle.is_null() {
+            return Err(Error::Initialization);
+        }
+
+        let disk = Self(handle);
+
+        // Records owner and group names alongside their ids
+        let ret = unsafe { archive_sys::archive_read_disk_set_standard_lookup(handle) };
+        if ret != archive_sys::ARCHIVE_OK as i32 {
+            return Err(Error::from_handle(
+                handle,
+                ret,
+                "archive_read_disk_set_standard_lookup",
+            ));
+        }
+
+        Ok(disk)
+    }
+
+    /// Reads the metadata of the file at `source` (without
+    /// following it if it is a symlink) into an entry
+    /// named `name`
+    pub(crate) fn entry(&self, source: &Path, name: &Path) -> Result<OwnedEntry> {
+        let entry = OwnedEntry::new()?;
+        let pathname = CString::new(name.as_os_str().as_encoded_bytes())?;
+        let sourcepath = CString::new(source.as_os_str().as_encoded_bytes())?;
+
+        unsafe {
+            archive_sys::archive_entry_copy_pathname(entry.0, pathname.as_ptr());
+            archive_sys::archive_entry_copy_sourcepath(entry.0, sourcepath.as_ptr());
+        }
+
+        let ret = unsafe {
+            archive_sys::archive_read_disk_entry_from_file(self.0, entry.0, -1, std::ptr::null())
+        };
+        check(self.0, ret, "archive_read_disk_entry_from_file", source)?;
+
+        Ok(entry)
+    }
+
+    /// Applies the symlink policy and behaviour of `options`
+    fn configure(&self, options: &AddPathOptions) -> Result<()> {
+        let (ret, operation) = unsafe {
+            match options.symlinks {
+                Symlinks::Physical => (
+                    archive_sys::archive_read_disk_set_symlink_physical(self.0),
+                    "archive_read_disk_set_symlink_physical",
+                ),
+                Symlinks::Logical => (
+                    archive_sys::archive_read_disk_set_symlink_logical(self.0),
+                    "archive_read_disk_set_symlink_logical",
+                ),
+                Symlinks::CommandLine => (
+                    archive_sys::archive_read_disk_set_symlink_hybrid(self.0),
+                    "archive_read_disk_set_symlink_hybrid",
+                ),
+            }
+        };
+
+        if ret != archive_sys::ARCHIVE_OK as i32 {
+            return Err(Error::from_handle(self.0, ret, operation));
+        }
+
+        let flags = [
+            (
+                options.one_file_system,
+                archive_sys::ARCHIVE_READDISK_NO_TRAVERSE_MOUNTS,
+            ),
+            (
+                options.honor_nodump,
+                archive_sys::ARCHIVE_READDISK_HONOR_NODUMP,
+            ),
+            (
+                options.restore_atime,
+                archive_sys::ARCHIVE_READDISK_RESTORE_ATIME,
+            ),
+        ]
+        .into_iter()
+        .filter(|(enabled, _)| *enabled)
+        .fold(0, |flags, (_, flag)| flags | flag);
+
+        let ret = unsafe { archive_sys::archive_read_disk_set_behavior(self.0, flags as i32) };
+        if ret != archive_sys::ARCHIVE_OK as i32 {
+            return Err(Error::from_handle(
+                self.0,
+                ret,
+                "archive_read_disk_set_behavior",
+            ));
         }
 
         Ok(())
     }
+
+    /// Starts walking the tree at `path`
+    fn open(&self, path: &Path) -> Result<()> {
+        let c_path = CString::new(path.as_os_str().as_encoded_bytes())?;
+        let ret = unsafe { archive_sys::archive_read_disk_open(self.0, c_path.as_ptr()) };
+
+        check(self.0, ret, "archive_read_disk_open", path)
+    }
+
+    /// Reads the next file of the tree into `entry`,
+    /// returning `false` once the walk is over
+    fn next_header(&self, entry: &OwnedEntry) -> Result<bool> {
+        let ret = unsafe { archive_sys::archive_read_next_header2(self.0, entry.0) };
+
+        if ret == archive_sys::ARCHIVE_EOF as i32 {
+            return Ok(false);
+        }
+
+        let path = unsafe { path_of(archive_sys::archive_entry_pathname(entry.0)) };
+        check(
+            self.0,
+            ret,
+            "archive_read_next_header2",
+            path.as_deref().unwrap_or(Path::new("")),
+        )?;
+
+        Ok(true)
+    }
+
+    /// Walks into the directory that was just read, if it
+    /// is one
+    fn descend(&self) -> Result<()> {
+        if unsafe { archive_sys::archive_read_disk_can_descend(self.0) } == 0 {
+            return Ok(());
+        }
+
+        let ret = unsafe { archive_sys::archive_read_disk_descend(self.0) };
+        if ret != archive_sys::ARCHIVE_OK as i32 {
+            return Err(Error::from_handle(self.0, ret, "archive_read_disk_descend"));
+        }
+
+        Ok(())
+    }
+
+    /// Copies the data of the file that was just read into
+    /// `writer`, filling holes with zeros, which formats
+    /// that record sparse files leave out again
+    fn copy_data(
+        &self,
+        writer: &mut EntryWriter<'_>,
+        source: &Path,
+        zeros: &mut Vec<u8>,
+    ) -> Result<()> {
+        loop {
+            let mut block: *const c_void = std::ptr::null();
+            let mut size = 0;
+            let mut offset = 0;
+
+            let ret = unsafe {
+                archive_sys::archive_read_data_block(self.0, &mut block, &mut size, &mut offset)
+            };
+
+            if ret == archive_sys::ARCHIVE_EOF as i32 {
+                break;
+            }
+
+            check(self.0, ret, "archive_read_data_block", source)?;
+            writer.write_zeros(
+                (offset.max(0) as u64).saturating_sub(writer.written()),
+                zeros,
+            )?;
+
+            if size > 0 && !block.is_null() {
+                let mut data = unsafe { std::slice::from_raw_parts(block as *const u8, size) };
+
+                while !data.is_empty() {
+                    let written = writer.write_data(data)?;
+                    data = &data[written..];
+                }
+            }
+        }
+
+        // The file may end with a hole
+        writer.write_zeros(writer.remaining().unwrap_or_default(), zeros)
+    }
+}
+
+impl Drop for DiskReader {
+    fn drop(&mut self) {
+        let ret = unsafe { archive_sys::archive_read_free(self.0) };
+
+        if ret != archive_sys::ARCHIVE_OK as i32 {
+            error!("archive_read_free failed with code {}", ret);
+        }
+    }
+}
+
+impl Drop for ArchiveWriter {
+    fn drop(&mut self) {
+        if let Err(e) = self.free() {
+            error!("Failed to finish archive writer: {}", e);
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::core::{ArchiveFilter, ArchiveFormat};
+    use sha2::{Digest, Sha256};
 
     #[test]
     fn archive() {
         let opts = ArchiveOptions::builder()
-            .filter(ArchiveFilter::Gzip)
-            .format(ArchiveFormat::Tar)
+            .filters([ArchiveFilter::Gzip])
+            .formats([ArchiveFormat::Tar])
+            .build();
+
+        let path = std::env::temp_dir().join("very-cool-archive.tar.gz");
+        let writer = ArchiveWriter::builder().handle_opts(opts).path(path).open();
+
+        assert!(writer.is_ok());
+    }
+
+    #[test]
+    fn finish() {
+        let opts = ArchiveOptions::builder()
+            .filters([ArchiveFilter::Gzip])
+            .formats([ArchiveFormat::Tar])
             .build();
 
-        let _writer = ArchiveWriter::builder()
+        let path = std::env::temp_dir().join("archive-finish.tar.gz");
+        let writer = ArchiveWriter::builder()
             .handle_opts(opts)
-            .path("very-cool-archive.tar.gz")
+            .path(&path)
+            .open()
+            .unwrap();
+
+        assert!(writer.finish().is_ok());
+
+        // The end-of-archive trailer has been written
+        assert!(std::fs::metadata(&path).unwrap().len() > 0);
+    }
+
+    #[test]
+    fn add_entries() {
+        let path = std::env::temp_dir().join("archive-entries.tar.gz");
+        let mut writer = ArchiveWriter::builder().path(&path).open().unwrap();
+        let mtime = SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1_700_000_000);
+
+        writer.add_dir("config").unwrap();
+        writer
+            .add_bytes("config/app.toml", b"debug = false")
+            .unwrap();
+        writer.add_symlink("app.toml", "config/app.toml").unwrap();
+
+        let header = EntryHeader::builder()
+            .path("run.sh")
+            .size(9)
+            .mode(0o755)
+            .uid(1000)
+            .uname("app")
+            .mtime(mtime)
+            .build();
+        writer.add_entry(&header, &b"#!/bin/sh"[..]).unwrap();
+
+        let short = EntryHeader::builder().path("short").size(10).build();
+        let error = writer
+            .add_entry(&short, &b"more than ten bytes"[..])
+            .unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidInput);
+
+        let error = writer.add_entry(&short, &b"short"[..]).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::UnexpectedEof);
+
+        let unknown = EntryHeader::builder().path("unsized").build();
+        let error = writer.add_entry(&unknown, &b"data"[..]).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidInput);
+
+        writer.finish().unwrap();
+
+        let entries = crate::list(&path).unwrap();
+        let find = |path: &str| {
+            entries
+                .iter()
+                .find(|entry| entry.path == Path::new(path))
+                .unwrap()
+        };
+
+        assert_eq!(find("config").kind, EntryKind::Directory);
+        assert_eq!(find("config").mode, 0o755);
+        assert_eq!(find("config/app.toml").size, Some(13));
+        assert_eq!(
+            find("app.toml").symlink.as_deref(),
+            Some(Path::new("config/app.toml"))
+        );
+
+        let script = find("run.sh");
+        assert_eq!(script.mode, 0o755);
+        assert_eq!(script.uid, 1000);
+        assert_eq!(script.uname.as_deref(), Some("app"));
+        assert_eq!(script.mtime, Some(mtime));
+        assert!(entries
+            .iter()
+            .all(|entry| entry.path != Path::new("unsized")));
+    }
+
+    #[test]
+    fn entry_writer() {
+        use std::io::{Read, Write};
+
+        let path = std::env::temp_dir().join("archive-entry-writer.tar");
+        let mut writer = ArchiveWriter::builder().path(&path).open().unwrap();
+
+        let lines: Vec<_> = (0..100).map(|line| format!("line {line:03}\n")).collect();
+        let header = EntryHeader::builder()
+            .path("log.txt")
+            .size(lines.len() as u64 * 9)
+            .build();
+
+        let mut entry = writer.start_entry(&header).unwrap();
+        for line in &lines {
+            entry.write_all(line.as_bytes()).unwrap();
+        }
+
+        assert_eq!(entry.remaining(), Some(0));
+        let error = entry.write_all(b"extra").unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidInput);
+        entry.finish().unwrap();
+
+        let header = EntryHeader::builder().path("short.txt").size(10).build();
+        let mut entry = writer.start_entry(&header).unwrap();
+        entry.write_all(b"short").unwrap();
+        assert_eq!(entry.written(), 5);
+
+        let error = entry.finish().unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::UnexpectedEof);
+
+        // Dropping an entry finishes it too
+        let header = EntryHeader::builder().path("dropped.txt").size(4).build();
+        writer
+            .start_entry(&header)
+            .unwrap()
+            .write_all(b"data")
+            .unwrap();
+
+        writer.finish().unwrap();
+
+        let mut reader = crate::ArchiveReader::builder()
+            .source(&path)
+            .open()
+            .unwrap();
+        let mut contents = Vec::new();
+
+        while let Some(mut entry) = reader.next_entry().unwrap() {
+            let mut data = String::new();
+            entry.read_to_string(&mut data).unwrap();
+            contents.push((entry.archive_path().to_path_buf(), data));
+        }
+
+        assert_eq!(contents[0].1, lines.concat());
+        // Short entries are padded to their declared size
+        assert_eq!(contents[1].1, "short\0\0\0\0\0");
+        assert_eq!(
+            contents[2],
+            (PathBuf::from("dropped.txt"), "data".to_string())
+        );
+    }
+
+    #[test]
+    fn add_stream() {
+        use std::io::Read;
+
+        let data: Vec<u8> = (0..100_000u32).flat_map(u32::to_le_bytes).collect();
+        let base = std::env::temp_dir();
+
+        for name in ["archive-stream.tar", "archive-stream.zip"] {
+            let path = base.join(name);
+            let mut writer = ArchiveWriter::builder()
+                .path(&path)
+                .spool_threshold(1024)
+                .open()
+                .unwrap();
+
+            // Neither slices chained together nor the header
+            // tell the writer how much data there is
+            let header = EntryHeader::builder().path("small").build();
+            let size = writer.add_stream(&header, &data[..16]).unwrap();
+            assert_eq!(size, 16);
+
+            let header = EntryHeader::builder().path("large").build();
+            let stream = data[..1000].chain(&data[1000..]);
+            let size = writer.add_stream(&header, stream).unwrap();
+            assert_eq!(size, data.len() as u64);
+
+            let header = EntryHeader::builder()
+                .path("directory")
+                .kind(EntryKind::Directory)
+                .build();
+            assert!(writer.add_stream(&header, std::io::empty()).is_err());
+
+            writer.finish().unwrap();
+
+            let mut reader = crate::ArchiveReader::builder()
+                .source(&path)
+                .open()
+                .unwrap();
+
+            let mut small = Vec::new();
+            reader
+                .next_entry()
+                .unwrap()
+                .unwrap()
+                .read_to_end(&mut small)
+                .unwrap();
+            assert_eq!(small, data[..16]);
+
+            let mut large = Vec::new();
+            reader
+                .next_entry()
+                .unwrap()
+                .unwrap()
+                .read_to_end(&mut large)
+                .unwrap();
+            assert_eq!(large, data);
+        }
+
+        let header = EntryHeader::builder().path("unsized").build();
+
+        for (name, streams) in [
+            ("archive-unsized.tar", false),
+            ("archive-unsized.zip", true),
+        ] {
+            let mut writer = ArchiveWriter::builder()
+                .path(base.join(name))
+                .open()
+                .unwrap();
+            assert_eq!(writer.start_entry(&header).is_ok(), streams);
+        }
+    }
+
+    #[test]
+    fn entry_names() {
+        assert_eq!(
+            entry_name(Path::new("/usr/share/doc")),
+            Path::new("usr/share/doc")
+        );
+        assert_eq!(
+            entry_name(Path::new("./src/../lib.rs")),
+            Path::new("src/lib.rs")
+        );
+        assert_eq!(entry_name(Path::new("..")), Path::new("."));
+    }
+
+    #[test]
+    fn add_path() {
+        let base = std::env::temp_dir().join("archive-add-path");
+        let _ = std::fs::remove_dir_all(&base);
+
+        let tree = base.join("tree");
+        std::fs::create_dir_all(tree.join("keep")).unwrap();
+        std::fs::create_dir_all(tree.join("skip")).unwrap();
+        std::fs::write(tree.join("keep/file.txt"), "kept").unwrap();
+        std::fs::write(tree.join("skip/file.txt"), "skipped").unwrap();
+        std::fs::write(tree.join("ignored.tmp"), "ignored").unwrap();
+
+        // Written into the tree it archives
+        let path = tree.join("tree.tar");
+        let mut writer = ArchiveWriter::builder().path(&path).open().unwrap();
+
+        let seen = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let options = {
+            let seen = Arc::clone(&seen);
+
+            AddPathOptions::builder()
+                .name("renamed")
+                .filter(move |file| {
+                    seen.lock().unwrap().push(file.path.clone());
+                    file.path.file_name().is_none_or(|name| name != "skip")
+                        && file.path.extension().is_none_or(|ext| ext != "tmp")
+                })
+                .build()
+        };
+
+        writer.add_path(&tree, &options).unwrap();
+        writer.finish().unwrap();
+
+        let mut paths: Vec<_> = crate::list(&path)
+            .unwrap()
+            .into_iter()
+            .map(|entry| entry.path)
+            .collect();
+        paths.sort();
+
+        assert_eq!(
+            paths,
+            ["renamed", "renamed/keep", "renamed/keep/file.txt"].map(PathBuf::from)
+        );
+
+        // Nothing below a filtered directory is looked at
+        let seen = seen.lock().unwrap();
+        assert!(seen.contains(&tree.join("skip")));
+        assert!(!seen.contains(&tree.join("skip/file.txt")));
+
+        let mut reader = crate::ArchiveReader::builder()
+            .source(&path)
+            .open()
+            .unwrap();
+        let files = reader.list().unwrap();
+        let file = files
+            .iter()
+            .find(|entry| entry.path == Path::new("renamed/keep/file.txt"))
+            .unwrap();
+        let metadata = std::fs::metadata(tree.join("keep/file.txt")).unwrap();
+
+        assert_eq!(file.size, Some(4));
+        assert_eq!(
+            file.mtime,
+            metadata.modified().ok().map(|mtime| {
+                // ustar stores whole seconds
+                let secs = mtime
+                    .duration_since(SystemTime::UNIX_EPOCH)
+                    .unwrap()
+                    .as_secs();
+                SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(secs)
+            })
+        );
+        assert!(file.uname.is_some());
+    }
+
+    #[test]
+    fn add_path_symlinks() {
+        let base = std::env::temp_dir().join("archive-symlinks");
+        let _ = std::fs::remove_dir_all(&base);
+
+        std::fs::create_dir_all(base.join("target")).unwrap();
+        std::fs::create_dir_all(base.join("tree")).unwrap();
+        std::fs::write(base.join("target/file.txt"), "target").unwrap();
+        std::os::unix::fs::symlink(base.join("target"), base.join("tree/inner")).unwrap();
+        std::os::unix::fs::symlink(base.join("tree"), base.join("root")).unwrap();
+
+        let kinds = |symlinks: Symlinks| {
+            let path = base.join(format!("{symlinks:?}.tar"));
+            let mut writer = ArchiveWriter::builder().path(&path).open().unwrap();
+            let options = AddPathOptions::builder()
+                .name("root")
+                .symlinks(symlinks)
+                .restore_atime(true)
+                .one_file_system(true)
+                .build();
+
+            writer.add_path(base.join("root"), &options).unwrap();
+            writer.finish().unwrap();
+
+            let mut kinds: Vec<_> = crate::list(&path)
+                .unwrap()
+                .into_iter()
+                .map(|entry| (entry.path, entry.kind))
+                .collect();
+            kinds.sort_by(|a, b| a.0.cmp(&b.0));
+            kinds
+        };
+
+        assert_eq!(
+            kinds(Symlinks::Physical),
+            [(PathBuf::from("root"), EntryKind::Symlink)]
+        );
+        assert_eq!(
+            kinds(Symlinks::CommandLine),
+            [
+                (PathBuf::from("root"), EntryKind::Directory),
+                (PathBuf::from("root/inner"), EntryKind::Symlink),
+            ]
+        );
+        assert_eq!(
+            kinds(Symlinks::Logical),
+            [
+                (PathBuf::from("root"), EntryKind::Directory),
+                (PathBuf::from("root/inner"), EntryKind::Directory),
+                (PathBuf::from("root/inner/file.txt"), EntryKind::File),
+            ]
+        );
+    }
+
+    #[test]
+    fn memory() {
+        for (filter, format) in [
+            (ArchiveFilter::Gzip, ArchiveFormat::TarPaxRestricted),
+            (ArchiveFilter::None, ArchiveFormat::Zip),
+        ] {
+            let opts = ArchiveOptions::builder()
+                .filters([filter])
+                .formats([format])
+                .build();
+
+            let mut writer = ArchiveWriter::memory().handle_opts(opts).open().unwrap();
+            writer.add_dir("dir").unwrap();
+            writer.add_bytes("dir/file.txt", b"in memory").unwrap();
+
+            let data = writer.finish_into_vec().unwrap();
+            let entries = crate::list(data.clone()).unwrap();
+            assert_eq!(entries.len(), 2);
+            assert_eq!(entries[1].path, Path::new("dir/file.txt"));
+
+            let mut reader = crate::ArchiveReader::builder().source(data).open().unwrap();
+            let mut contents = String::new();
+            while let Some(mut entry) = reader.next_entry().unwrap() {
+                if entry.metadata().is_file() {
+                    entry.read_to_string(&mut contents).unwrap();
+                }
+            }
+            assert_eq!(contents, "in memory");
+        }
+
+        // There is no file name to pick the format from
+        assert!(ArchiveWriter::memory().open().is_err());
+        assert!(ArchiveWriter::stream()
+            .target(std::io::sink())
+            .open()
+            .is_err());
+
+        let path = std::env::temp_dir().join("archive-not-memory.tar");
+        let writer = ArchiveWriter::builder().path(path).open().unwrap();
+        assert!(writer.finish_into_vec().is_err());
+    }
+
+    #[test]
+    fn stream() {
+        /// Hashes what is written, like `sha256sum`
+        struct Hasher(Arc<std::sync::Mutex<Sha256>>);
+
+        impl Write for Hasher {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                self.0.lock().unwrap().update(buf);
+                Ok(buf.len())
+            }
+
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let opts = || {
+            ArchiveOptions::builder()
+                .filters([ArchiveFilter::None])
+                .formats([ArchiveFormat::TarPaxRestricted])
+                .build()
+        };
+        let header = EntryHeader::builder()
+            .path("file.txt")
+            .size(9)
+            .mtime(SystemTime::UNIX_EPOCH)
+            .build();
+
+        let digest = Arc::new(std::sync::Mutex::new(Sha256::new()));
+        let mut writer = ArchiveWriter::stream()
+            .target(Hasher(Arc::clone(&digest)))
+            .handle_opts(opts())
+            .open()
+            .unwrap();
+        writer.add_entry(&header, &b"streaming"[..]).unwrap();
+        writer.finish().unwrap();
+
+        let mut writer = ArchiveWriter::memory().handle_opts(opts()).open().unwrap();
+        writer.add_entry(&header, &b"streaming"[..]).unwrap();
+        let data = writer.finish_into_vec().unwrap();
+
+        // Unlike memory, streams are padded to a full block
+        assert!(data.len() < 10240);
+        let mut padded = data.clone();
+        padded.resize(10240, 0);
+        assert_eq!(
+            digest.lock().unwrap().clone().finalize(),
+            Sha256::digest(&padded)
+        );
+
+        /// Fails every write
+        struct Broken;
+
+        impl Write for Broken {
+            fn write(&mut self, _: &[u8]) -> std::io::Result<usize> {
+                Err(std::io::Error::other("disk on fire"))
+            }
+
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let mut writer = ArchiveWriter::stream()
+            .target(Broken)
+            .handle_opts(opts())
+            .open()
+            .unwrap();
+        writer.add_entry(&header, &b"streaming"[..]).unwrap();
+
+        let error = writer.finish().unwrap_err();
+        assert!(error.to_string().contains("disk on fire"), "{error}");
+    }
+
+    #[test]
+    fn format_by_extension() {
+        let base = std::env::temp_dir().join("archive-by-ext");
+        let _ = std::fs::remove_dir_all(&base);
+        std::fs::create_dir_all(&base).unwrap();
+
+        let written = |name: &str, opts: ArchiveOptions| {
+            let path = base.join(name);
+            let mut writer = ArchiveWriter::builder()
+                .path(&path)
+                .handle_opts(opts)
+                .open()?;
+            writer.add_bytes("file.txt", b"by extension")?;
+            writer.finish()?;
+
+            let mut reader = crate::ArchiveReader::builder().source(path).open()?;
+            reader.next_entry()?;
+
+            Ok::<_, Error>((
+                reader.format().map(ArchiveFormat::family),
+                reader.is_filtered(),
+            ))
+        };
+
+        for (name, format, filtered) in [
+            ("out.tgz", ArchiveFormat::Tar, true),
+            ("out.tar.xz", ArchiveFormat::Tar, true),
+            ("out.tar", ArchiveFormat::Tar, false),
+            ("out.zip", ArchiveFormat::Zip, false),
+            ("out.7z", ArchiveFormat::P7zip, false),
+            ("out.cpio", ArchiveFormat::Cpio, false),
+            ("out.a", ArchiveFormat::Ar, false),
+        ] {
+            let result = written(name, ArchiveOptions::default()).unwrap();
+            assert_eq!(result, (Some(format), filtered), "{name}");
+        }
+
+        // An explicit format keeps the filter from the extension,
+        // and an explicit filter keeps the format
+        let zip = ArchiveOptions::builder()
+            .formats([ArchiveFormat::Zip])
+            .build();
+        assert_eq!(
+            written("zip.tgz", zip.clone()).unwrap(),
+            (Some(ArchiveFormat::Zip), true)
+        );
+        assert_eq!(
+            written("zip.bin", zip).unwrap(),
+            (Some(ArchiveFormat::Zip), false)
+        );
+
+        let xz = ArchiveOptions::builder()
+            .filters([ArchiveFilter::Xz])
+            .build();
+        assert_eq!(
+            written("xz.tar", xz).unwrap(),
+            (Some(ArchiveFormat::Tar), true)
+        );
+
+        let unknown = written("out.bin", ArchiveOptions::default()).unwrap_err();
+        assert!(matches!(unknown, Error::UnknownExtension(path) if path == base.join("out.bin")));
+        assert!(!base.join("out.bin").exists());
+
+        assert!(matches!(
+            ArchiveWriter::memory().open(),
+            Err(Error::FormatRequired)
+        ));
+
+        let program = ArchiveOptions::builder()
+            .filters([ArchiveFilter::Program])
             .build();
+        assert!(matches!(
+            written("program.tar", program),
+            Err(Error::UnsupportedFilter(ArchiveFilter::Program))
+        ));
     }
 }